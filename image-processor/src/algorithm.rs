@@ -1,21 +1,36 @@
 use genevo::{
+    algorithm::EvaluatedPopulation,
+    ga::GeneticAlgorithm,
     genetic::{Children, Parents},
-    operator::{prelude::*, CrossoverOp, GeneticOperator, MutationOp},
+    operator::{prelude::*, CrossoverOp, GeneticOperator, MutationOp, SelectionOp},
     prelude::*,
     random::Rng,
+    simulation::simulator::Simulator,
+    termination::combinator::Or,
 };
-use image::RgbImage;
+use image::RgbaImage;
+use rand::seq::SliceRandom;
 use rand_core::SeedableRng;
 use rand_pcg::Pcg64;
+use serde::Serialize;
 use std::cmp::Ordering;
+use std::ops::ControlFlow;
+use std::sync::Arc;
 
+use crate::cost::CostFunction;
+use crate::error::CollageError;
 use crate::layout::{Layout, LayoutNode};
+use crate::options::{GeneticParams, LayoutOptions};
+use crate::templates::Template;
 
 // Phenotype is layout node.
 // Genotype is layout.
 
+// Consults the cost function, see the cost module.
 #[derive(Clone, Debug)]
-struct FitnessCalc;
+struct FitnessCalc {
+    cost_function: Arc<dyn CostFunction>,
+}
 
 impl<'a> Genotype for Layout<'a> {
     type Dna = LayoutNode<'a>;
@@ -67,7 +82,7 @@ impl Fitness for FitnessFloat {
 
 impl FitnessFunction<Layout<'_>, FitnessFloat> for FitnessCalc {
     fn fitness_of(&self, layout: &Layout) -> FitnessFloat {
-        FitnessFloat(layout.cost())
+        FitnessFloat(self.cost_function.cost(layout))
     }
 
     fn average(&self, fitness_values: &[FitnessFloat]) -> FitnessFloat {
@@ -83,6 +98,64 @@ impl FitnessFunction<Layout<'_>, FitnessFloat> for FitnessCalc {
     }
 }
 
+// Same as genevo's MaximizeSelector, except that the pairs of parents are packed into batches.
+//
+// On native targets, genevo breeds 50 or more batches of parents in parallel, giving each thread
+// its own RNG by jumping ahead in the main one. In wasm, it always breeds them one after another
+// with the main RNG. Keeping the number of batches below that threshold makes both go through the
+// same sequential path, so that a seed results in the same layout everywhere.
+#[derive(Debug, Clone)]
+struct BatchedSelector {
+    selection_ratio: f64,
+}
+
+impl BatchedSelector {
+    const MAX_BATCHES: usize = 49;
+
+    pub fn new(selection_ratio: f64) -> Self {
+        BatchedSelector { selection_ratio }
+    }
+}
+
+impl GeneticOperator for BatchedSelector {
+    fn name() -> String {
+        "Batched-Maximizing-Truncation-Selection".to_string()
+    }
+}
+
+impl<'a> SelectionOp<Layout<'a>, FitnessFloat> for BatchedSelector {
+    fn select_from<R>(
+        &self,
+        evaluated: &EvaluatedPopulation<Layout<'a>, FitnessFloat>,
+        _: &mut R,
+    ) -> Vec<Parents<Layout<'a>>>
+    where
+        R: Rng + Sized,
+    {
+        let individuals = evaluated.individuals();
+        let fitness_values = evaluated.fitness_values();
+
+        // From the best to the worst layout. The sort is stable, so ties don't depend on anything
+        // but the order of the population.
+        let mut mating_pool: Vec<usize> = (0..fitness_values.len()).collect();
+        mating_pool.sort_by(|x, y| fitness_values[*y].cmp(&fitness_values[*x]));
+
+        let pair_count = (individuals.len() as f64 * self.selection_ratio + 0.5).floor() as usize;
+        let pairs_per_batch = pair_count.div_ceil(Self::MAX_BATCHES).max(1);
+        let parents: Vec<Layout<'a>> = mating_pool
+            .iter()
+            .cycle()
+            .take(pair_count * 2)
+            .map(|&index| individuals[index].clone())
+            .collect();
+
+        parents
+            .chunks(pairs_per_batch * 2)
+            .map(|batch| batch.to_vec())
+            .collect()
+    }
+}
+
 #[derive(Debug, Clone)]
 struct LayoutCrossover;
 
@@ -98,21 +171,23 @@ impl GeneticOperator for LayoutCrossover {
     }
 }
 
+// Crosses over consecutive pairs of parents, see BatchedSelector.
 impl<'a> CrossoverOp<Layout<'a>> for LayoutCrossover {
     fn crossover<R>(&self, parents: Parents<Layout<'a>>, rng: &mut R) -> Children<Layout<'a>>
     where
         R: Rng + Sized,
     {
-        if let ([parent_1, parent_2], _) = parents.split_at(2) {
-            let mut child_1 = parent_1.clone();
-            let mut child_2 = parent_2.clone();
+        parents
+            .chunks_exact(2)
+            .flat_map(|pair| {
+                let mut child_1 = pair[0].clone();
+                let mut child_2 = pair[1].clone();
 
-            child_1.crossover_random_subtrees(&mut child_2, rng);
+                child_1.crossover_random_subtrees(&mut child_2, rng);
 
-            vec![child_1, child_2]
-        } else {
-            unreachable!();
-        }
+                [child_1, child_2]
+            })
+            .collect()
     }
 }
 
@@ -138,7 +213,15 @@ impl<'a> MutationOp<Layout<'a>> for LayoutMutation {
     {
         let mut mutated = genome.clone();
 
-        match rng.gen_range(0..=3) {
+        // With a locked aspect ratio, changing just one side of the canvas is meaningless, as the
+        // other side is derived from the width anyway.
+        let mutation = if genome.options().aspect_ratio.is_some() {
+            *[0, 3].choose(rng).unwrap()
+        } else {
+            rng.gen_range(0..=3)
+        };
+
+        match mutation {
             0 => {
                 mutated.swap_random_node_pair(rng);
             }
@@ -160,55 +243,454 @@ impl<'a> MutationOp<Layout<'a>> for LayoutMutation {
     }
 }
 
-pub fn generate_layout<'a, R>(
-    images: &'a [RgbImage],
+type LayoutSimulator<'a> = Simulator<LayoutAlgorithm<'a>, LayoutTermination<'a>>;
+type LayoutAlgorithm<'a> = GeneticAlgorithm<
+    Layout<'a>,
+    FitnessFloat,
+    FitnessCalc,
+    BatchedSelector,
+    LayoutCrossover,
+    LayoutMutation,
+    ElitistReinserter<Layout<'a>, FitnessFloat, FitnessCalc>,
+>;
+type LayoutTermination<'a> =
+    Or<FitnessLimit<Layout<'a>, FitnessFloat>, GenerationLimit, LayoutAlgorithm<'a>>;
+
+// Where the search is at after a generation. Handed over to JS as is, hence the milliseconds.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct SearchProgress {
+    pub generation: u64,
+    pub generation_limit: u64,
+    pub best_cost: f64,
+    // Whether the best layout changed in this generation.
+    pub improved: bool,
+    pub elapsed_ms: i64,
+    // Set once the search is over.
+    pub stop_reason: Option<StopReason>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StopReason {
+    GenerationLimit,
+    TimeLimit,
+    // The best cost didn't improve by more than the epsilon for too many generations.
+    Stagnation,
+    // A layout with zero cost, which can't get any better.
+    PerfectLayout,
+    // The caller broke out of the search.
+    Cancelled,
+}
+
+// Runs the genetic algorithm one generation at a time, keeping track of the best layout found so
+// far. This lets the caller report progress, show intermediate layouts and stop the search early.
+pub struct LayoutSearch<'a> {
+    simulator: LayoutSimulator<'a>,
+    params: GeneticParams,
+    best: Option<(Layout<'a>, f64)>,
+    // The generation and the best cost at the time of the last significant improvement.
+    last_improvement: Option<(u64, f64)>,
+    progress: Option<SearchProgress>,
+    started_at: chrono::DateTime<chrono::Utc>,
+    stop_reason: Option<StopReason>,
+}
+
+impl<'a> LayoutSearch<'a> {
+    pub fn new<R>(
+        images: &'a [RgbaImage],
+        options: &LayoutOptions,
+        params: &GeneticParams,
+        cost_function: Arc<dyn CostFunction>,
+        rng: &mut R,
+        seed: Option<u64>,
+    ) -> Result<Self, CollageError>
+    where
+        R: Rng + Sized,
+    {
+        let population_size = params.population_size;
+
+        // Templates give the population a few reasonable layouts to start with, the rest is random.
+        let template_layouts: Vec<Layout> = Template::ALL
+            .iter()
+            .filter_map(|template| template.layout(images).ok())
+            .take(population_size)
+            .collect();
+        let random_layouts = (template_layouts.len()..population_size)
+            .map(|_| Layout::new(images, rng))
+            .collect::<Result<Vec<_>, _>>()?;
+        let initial_population = Population::with_individuals(
+            template_layouts
+                .into_iter()
+                .chain(random_layouts)
+                .map(|layout| layout.with_options(*options))
+                .collect(),
+        );
+
+        let fitness_calc = FitnessCalc { cost_function };
+        let simulator = simulate(
+            genetic_algorithm()
+                .with_evaluation(fitness_calc.clone())
+                .with_selection(BatchedSelector::new(params.selection_ratio))
+                .with_crossover(LayoutCrossover::new())
+                .with_mutation(LayoutMutation::new())
+                .with_reinsertion(ElitistReinserter::new(
+                    fitness_calc.clone(),
+                    true,
+                    params.reinsertion_ratio,
+                ))
+                .with_initial_population(initial_population)
+                .build(),
+        )
+        .until(or(
+            FitnessLimit::new(fitness_calc.highest_possible_fitness()),
+            GenerationLimit::new(params.generation_limit),
+        ));
+        let simulator = match seed {
+            Some(seed) => {
+                let mut rng = Pcg64::seed_from_u64(seed);
+                simulator.build_with_seed(rng.gen())
+            }
+            None => simulator.build(),
+        };
+
+        Ok(LayoutSearch {
+            simulator,
+            params: *params,
+            best: None,
+            last_improvement: None,
+            progress: None,
+            started_at: chrono::Utc::now(),
+            stop_reason: None,
+        })
+    }
+
+    // Processes a single generation. Does nothing once the search is finished, see StopReason.
+    pub fn step(&mut self) -> Result<SearchProgress, CollageError> {
+        if let (Some(_), Some(progress)) = (self.stop_reason, self.progress) {
+            return Ok(progress);
+        }
+
+        let (state, finished) = match self.simulator.step() {
+            Ok(SimResult::Intermediate(state)) => (state, false),
+            Ok(SimResult::Final(state, _, _, _)) => (state, true),
+            Err(err) => return Err(CollageError::LayoutGeneration(format!("{:?}", err))),
+        };
+
+        let best_solution = state.result.best_solution.solution;
+        let cost = best_solution.fitness.0;
+        let improved = match &self.best {
+            Some((_, best_cost)) => cost < *best_cost,
+            None => true,
+        };
+
+        if improved {
+            self.best = Some((best_solution.genome, cost));
+        }
+        let best_cost = self.best.as_ref().map_or(cost, |(_, cost)| *cost);
+
+        // genevo stops either at the generation limit or at the fitness limit of zero cost. Both
+        // can happen at once, in which case the layout being perfect is what matters.
+        if finished {
+            self.stop_reason = Some(if best_cost <= 0.0 {
+                StopReason::PerfectLayout
+            } else {
+                StopReason::GenerationLimit
+            });
+        }
+
+        // Tiny improvements don't count, otherwise the search could go on for ages shaving off
+        // fractions of a pixel.
+        match self.last_improvement {
+            Some((_, last_cost)) if cost >= last_cost - self.params.stagnation_epsilon => {}
+            _ => self.last_improvement = Some((state.iteration, cost)),
+        }
+
+        let elapsed_ms = (chrono::Utc::now() - self.started_at).num_milliseconds();
+
+        if self.stop_reason.is_none() {
+            if let Some(time_limit_ms) = self.params.time_limit_ms {
+                if elapsed_ms >= time_limit_ms as i64 {
+                    self.stop_reason = Some(StopReason::TimeLimit);
+                }
+            }
+        }
+
+        if let (None, Some(stagnation_limit), Some((generation, _))) = (
+            self.stop_reason,
+            self.params.stagnation_limit,
+            self.last_improvement,
+        ) {
+            if state.iteration - generation >= stagnation_limit {
+                self.stop_reason = Some(StopReason::Stagnation);
+            }
+        }
+
+        let progress = SearchProgress {
+            generation: state.iteration,
+            generation_limit: self.params.generation_limit,
+            best_cost,
+            improved,
+            elapsed_ms,
+            stop_reason: self.stop_reason,
+        };
+        self.progress = Some(progress);
+
+        Ok(progress)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.stop_reason.is_some()
+    }
+
+    pub fn stop_reason(&self) -> Option<StopReason> {
+        self.stop_reason
+    }
+
+    pub fn best(&self) -> Option<&Layout<'a>> {
+        self.best.as_ref().map(|(layout, _)| layout)
+    }
+
+    // Steps through the search until it's finished or until `on_progress` breaks out of it,
+    // then returns the best layout found so far along with the final progress, which says why the
+    // search stopped.
+    pub fn run<F>(
+        mut self,
+        mut on_progress: F,
+    ) -> Result<(Layout<'a>, SearchProgress), CollageError>
+    where
+        F: FnMut(&SearchProgress, &Layout<'a>) -> ControlFlow<()>,
+    {
+        let mut progress = self.step()?;
+
+        loop {
+            let best = self.best().expect("No best layout after a generation");
+
+            if on_progress(&progress, best).is_break() {
+                self.stop_reason.get_or_insert(StopReason::Cancelled);
+                progress.stop_reason = self.stop_reason;
+                break;
+            }
+
+            if self.is_finished() {
+                break;
+            }
+
+            progress = self.step()?;
+        }
+
+        log::debug!(
+            "Search stopped after {} generations: {:?}",
+            progress.generation,
+            progress.stop_reason
+        );
+
+        let (layout, _) = self.best.expect("No best layout after a generation");
+
+        Ok((layout, progress))
+    }
+}
+
+pub fn generate_layout<'a, R, F>(
+    images: &'a [RgbaImage],
+    options: &LayoutOptions,
+    params: &GeneticParams,
+    cost_function: Arc<dyn CostFunction>,
     rng: &mut R,
     seed: Option<u64>,
-) -> Result<Layout<'a>, String>
+    on_progress: F,
+) -> Result<(Layout<'a>, SearchProgress), CollageError>
 where
     R: Rng + Sized,
+    F: FnMut(&SearchProgress, &Layout<'a>) -> ControlFlow<()>,
 {
-    let population_size = if cfg!(debug_assertions) { 50 } else { 75 };
-    let generation_limit = if cfg!(debug_assertions) { 200 } else { 4_000 };
-    let selection_ratio = 0.7;
-    let num_individuals_per_parents = 2;
-    let reinsertion_ratio = 0.7;
-    // End of genevo params.
-
-    let initial_population = Population::with_individuals(
-        (0..population_size)
-            .map(|_| Layout::new(images, rng))
-            .collect(),
-    );
-
-    let layout_sim = simulate(
-        genetic_algorithm()
-            .with_evaluation(FitnessCalc)
-            .with_selection(MaximizeSelector::new(
-                selection_ratio,
-                num_individuals_per_parents,
-            ))
-            .with_crossover(LayoutCrossover::new())
-            .with_mutation(LayoutMutation::new())
-            .with_reinsertion(ElitistReinserter::new(FitnessCalc, true, reinsertion_ratio))
-            .with_initial_population(initial_population)
-            .build(),
-    )
-    .until(or(
-        FitnessLimit::new(FitnessCalc.highest_possible_fitness()),
-        GenerationLimit::new(generation_limit),
-    ));
-    let mut layout_sim = match seed {
-        Some(seed) => {
-            let mut rng = Pcg64::seed_from_u64(seed);
-            layout_sim.build_with_seed(rng.gen())
+    LayoutSearch::new(images, options, params, cost_function, rng, seed)?.run(on_progress)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cost::{CostTerm, WeightedCost, WeightedTerm};
+    use crate::options::{AspectRatio, MIN_POPULATION_SIZE};
+    use crate::test_utils::{images, FIVE_IMAGES};
+
+    fn search<'a>(images: &'a [RgbaImage], params: &GeneticParams) -> LayoutSearch<'a> {
+        search_with_cost(images, params, Arc::new(WeightedCost::default()))
+    }
+
+    fn search_with_cost<'a>(
+        images: &'a [RgbaImage],
+        params: &GeneticParams,
+        cost_function: Arc<dyn CostFunction>,
+    ) -> LayoutSearch<'a> {
+        let mut rng = Pcg64::seed_from_u64(1);
+        let options = LayoutOptions::default();
+
+        LayoutSearch::new(images, &options, params, cost_function, &mut rng, Some(1)).unwrap()
+    }
+
+    #[test]
+    fn search_stops_at_the_generation_limit() {
+        let images = images(&FIVE_IMAGES);
+        let params = GeneticParams {
+            generation_limit: 5,
+            ..GeneticParams::default()
+        };
+        let mut search = search(&images, &params);
+        let mut generations = vec![];
+
+        while !search.is_finished() {
+            generations.push(search.step().unwrap().generation);
         }
-        None => layout_sim.build(),
-    };
 
-    if let Ok(SimResult::Final(step, _, _, _)) = layout_sim.run() {
-        return Ok(step.result.best_solution.solution.genome);
+        assert_eq!(vec![1, 2, 3, 4, 5], generations);
+        assert!(search.best().is_some());
     }
 
-    Err("Something went wrong with layout_sim.run()".to_string())
+    #[test]
+    fn best_cost_never_gets_worse() {
+        let images = images(&FIVE_IMAGES);
+        let params = GeneticParams {
+            generation_limit: 30,
+            ..GeneticParams::default()
+        };
+        let mut costs = vec![];
+
+        search(&images, &params)
+            .run(|progress, best| {
+                assert_eq!(best.cost(), progress.best_cost);
+                costs.push(progress.best_cost);
+                ControlFlow::Continue(())
+            })
+            .unwrap();
+
+        assert_eq!(30, costs.len());
+        assert!(costs.windows(2).all(|pair| pair[1] <= pair[0]));
+    }
+
+    #[test]
+    fn breaking_out_of_the_search_returns_the_best_layout_so_far() {
+        let images = images(&FIVE_IMAGES);
+        let mut last_best = None;
+
+        let (layout, progress) = search(&images, &GeneticParams::default())
+            .run(|progress, best| {
+                last_best = Some(best.to_blueprint());
+
+                if progress.generation == 3 {
+                    ControlFlow::Break(())
+                } else {
+                    ControlFlow::Continue(())
+                }
+            })
+            .unwrap();
+
+        assert_eq!(last_best, Some(layout.to_blueprint()));
+        assert_eq!(Some(StopReason::Cancelled), progress.stop_reason);
+    }
+
+    #[test]
+    fn search_stops_once_it_stagnates() {
+        let images = images(&FIVE_IMAGES);
+        let params = GeneticParams {
+            generation_limit: 10_000,
+            stagnation_limit: Some(3),
+            // Far more than the whole cost of the first generation, so nothing counts as an
+            // improvement.
+            stagnation_epsilon: 1e9,
+            ..GeneticParams::default()
+        };
+        params.validate().unwrap();
+
+        let (_, progress) = search(&images, &params)
+            .run(|_, _| ControlFlow::Continue(()))
+            .unwrap();
+
+        assert_eq!(Some(StopReason::Stagnation), progress.stop_reason);
+        assert_eq!(4, progress.generation);
+    }
+
+    #[test]
+    fn search_runs_with_the_smallest_valid_population() {
+        let images = images(&FIVE_IMAGES);
+        let params = GeneticParams {
+            population_size: MIN_POPULATION_SIZE,
+            generation_limit: 5,
+            ..GeneticParams::default()
+        };
+        params.validate().unwrap();
+
+        let (_, progress) = search(&images, &params)
+            .run(|_, _| ControlFlow::Continue(()))
+            .unwrap();
+
+        assert_eq!(5, progress.generation);
+    }
+
+    #[test]
+    fn search_reports_reaching_the_generation_limit() {
+        let images = images(&FIVE_IMAGES);
+        let params = GeneticParams {
+            generation_limit: 5,
+            ..GeneticParams::default()
+        };
+
+        let (_, progress) = search(&images, &params)
+            .run(|_, _| ControlFlow::Continue(()))
+            .unwrap();
+
+        assert_eq!(Some(StopReason::GenerationLimit), progress.stop_reason);
+    }
+
+    #[test]
+    fn perfect_layouts_found_in_the_last_generation_are_reported_as_such() {
+        #[derive(Debug)]
+        struct AnythingGoes;
+
+        impl CostFunction for AnythingGoes {
+            fn cost(&self, _: &Layout) -> f64 {
+                0.0
+            }
+        }
+
+        let images = images(&FIVE_IMAGES);
+        let params = GeneticParams {
+            generation_limit: 1,
+            ..GeneticParams::default()
+        };
+
+        let (_, progress) = search_with_cost(&images, &params, Arc::new(AnythingGoes))
+            .run(|_, _| ControlFlow::Continue(()))
+            .unwrap();
+
+        assert_eq!(1, progress.generation);
+        assert_eq!(Some(StopReason::PerfectLayout), progress.stop_reason);
+    }
+
+    #[test]
+    fn search_minimises_the_given_cost_function() {
+        let images = images(&FIVE_IMAGES);
+        let params = GeneticParams {
+            generation_limit: 30,
+            ..GeneticParams::default()
+        };
+        let panorama = WeightedCost::new(vec![WeightedTerm {
+            weight: 1.0,
+            term: CostTerm::AspectRatio {
+                target: AspectRatio::new(3.0).unwrap(),
+            },
+        }])
+        .unwrap();
+        let mut first_cost = None;
+
+        let (layout, progress) = search_with_cost(&images, &params, Arc::new(panorama.clone()))
+            .run(|progress, best| {
+                assert_eq!(panorama.cost(best), progress.best_cost);
+                first_cost.get_or_insert(progress.best_cost);
+                ControlFlow::Continue(())
+            })
+            .unwrap();
+
+        assert_eq!(panorama.cost(&layout), progress.best_cost);
+        assert!(progress.best_cost < first_cost.unwrap());
+    }
 }
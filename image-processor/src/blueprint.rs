@@ -0,0 +1,751 @@
+// A serializable description of a layout, which can be stored and rendered again later with the
+// same or different images.
+//
+// A blueprint lists all nodes of the slicing tree, with the root node coming first. Internal nodes
+// point to their children by their position in the list, left child first. Leaf nodes say which
+// image goes in them:
+//
+//     {
+//       "version": 2,
+//       "width": 370,
+//       "height": 642,
+//       "nodes": [
+//         {"split": "H", "children": [1, 2]},
+//         {"image": 0, "hash": "8d4f0a1e5c3b2a19"},
+//         {"split": "V", "children": [3, 4]},
+//         {"image": 2},
+//         {"image": 1}
+//       ]
+//     }
+//
+// The image index refers to the order in which the images are passed when rendering the
+// blueprint. The hash is optional. If it's present, the leaf gets whichever image has the same
+// content, so that reordering the images doesn't silently move them around.
+//
+// Blueprints from before the versioned format are still accepted, see `from_legacy`.
+
+use crate::error::BlueprintError;
+use crate::expression;
+use crate::image_for_processing::ImageForProcessing;
+use image::RgbaImage;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+pub const BLUEPRINT_VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "BlueprintRepresentation")]
+pub struct LayoutBlueprint {
+    pub version: u32,
+    pub width: u32,
+    pub height: u32,
+    pub nodes: Vec<BlueprintNode>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum BlueprintNode {
+    Internal {
+        // "V" or "H".
+        split: String,
+        children: Vec<usize>,
+    },
+    Leaf {
+        image: usize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        hash: Option<String>,
+    },
+}
+
+impl LayoutBlueprint {
+    pub fn new(width: u32, height: u32, nodes: Vec<BlueprintNode>) -> Self {
+        LayoutBlueprint {
+            version: BLUEPRINT_VERSION,
+            width,
+            height,
+            nodes,
+        }
+    }
+
+    // The original format only described internal nodes, as `(label, indices of internal
+    // children)` pairs. So a graph representation of this form in JavaScript…
+    //
+    //     [
+    //       ["V",  [1, 2]],
+    //       ["H"], [ ]],
+    //       ["V"], [ ]],
+    //     ]
+    //
+    // …represents a graph which looks like this:
+    //
+    //          ┌───┐
+    //          │ V │
+    //          └───┘
+    //            │
+    //      ┌─────┴─────┐
+    //      ▼           ▼
+    //    ┌───┐       ┌───┐
+    //    │ H │       │ V │
+    //    └───┘       └───┘
+    //
+    // Then the images were sequentially added as leaf nodes to any internal node that had less
+    // than two children, starting from the first node to the last one. The same happens here,
+    // except that the leaves are written down explicitly.
+    pub fn from_legacy(
+        width: u32,
+        height: u32,
+        graph_representation: Vec<(String, Vec<usize>)>,
+    ) -> Self {
+        let mut nodes: Vec<BlueprintNode> = graph_representation
+            .iter()
+            .map(|(label, children)| BlueprintNode::Internal {
+                split: label.clone(),
+                children: children.clone(),
+            })
+            .collect();
+        let mut next_image = 0;
+
+        for i in 0..graph_representation.len() {
+            let missing_children = 2usize.saturating_sub(graph_representation[i].1.len());
+
+            for _ in 0..missing_children {
+                let leaf_index = nodes.len();
+                nodes.push(BlueprintNode::Leaf {
+                    image: next_image,
+                    hash: None,
+                });
+                next_image += 1;
+
+                if let BlueprintNode::Internal { children, .. } = &mut nodes[i] {
+                    children.push(leaf_index);
+                }
+            }
+        }
+
+        LayoutBlueprint::new(width, height, nodes)
+    }
+
+    // Creates a blueprint from a slicing tree expression such as `V(H(0,1),2)`, see the expression
+    // module for the syntax.
+    pub fn from_expression(
+        expression: &str,
+        width: u32,
+        height: u32,
+    ) -> Result<Self, BlueprintError> {
+        Ok(LayoutBlueprint::new(
+            width,
+            height,
+            expression::parse(expression)?,
+        ))
+    }
+
+    // The opposite of `from_expression`. Expressions don't carry the canvas dimensions nor image
+    // hashes, so those are lost.
+    pub fn to_expression(&self) -> Result<String, BlueprintError> {
+        let leaf_count = self
+            .nodes
+            .iter()
+            .filter(|node| matches!(node, BlueprintNode::Leaf { .. }))
+            .count();
+
+        self.validate(leaf_count)?;
+
+        Ok(expression::print(&self.nodes))
+    }
+
+    // Records the content hash of each image next to its index.
+    pub fn with_image_hashes(mut self, images: &[RgbaImage]) -> Self {
+        for node in self.nodes.iter_mut() {
+            if let BlueprintNode::Leaf { image, hash } = node {
+                *hash = images.get(*image).map(|image| image.content_hash());
+            }
+        }
+
+        self
+    }
+
+    // Figures out which of the images goes in each leaf. Returns a vector with an entry for each
+    // node, `None` for internal nodes. Expects a valid blueprint.
+    pub(crate) fn resolve_images(
+        &self,
+        images: &[RgbaImage],
+    ) -> Result<Vec<Option<usize>>, BlueprintError> {
+        let uses_hashes = self
+            .nodes
+            .iter()
+            .any(|node| matches!(node, BlueprintNode::Leaf { hash: Some(_), .. }));
+        let hashes: Vec<String> = if uses_hashes {
+            images.iter().map(|image| image.content_hash()).collect()
+        } else {
+            vec![]
+        };
+        // Leaves with only an index claim their images first, so that a hash can't pick an
+        // image which is already shown elsewhere. Identical images have the same hash, so each
+        // of them can be picked only once as well.
+        let mut used = vec![false; images.len()];
+        let mut resolved = vec![None; self.nodes.len()];
+
+        for (i, node) in self.nodes.iter().enumerate() {
+            if let BlueprintNode::Leaf { image, hash: None } = node {
+                if used[*image] {
+                    return Err(BlueprintError::DuplicateImage {
+                        node: i,
+                        image: *image,
+                    });
+                }
+                used[*image] = true;
+                resolved[i] = Some(*image);
+            }
+        }
+
+        for (i, node) in self.nodes.iter().enumerate() {
+            if let BlueprintNode::Leaf {
+                hash: Some(hash), ..
+            } = node
+            {
+                let matching = || (0..images.len()).filter(|&image| hashes[image] == *hash);
+                let image = match matching().find(|&image| !used[image]) {
+                    Some(image) => image,
+                    None => {
+                        return Err(match matching().next() {
+                            Some(image) => BlueprintError::DuplicateImage { node: i, image },
+                            None => BlueprintError::UnknownImageHash {
+                                node: i,
+                                hash: hash.clone(),
+                            },
+                        })
+                    }
+                };
+                used[image] = true;
+                resolved[i] = Some(image);
+            }
+        }
+
+        Ok(resolved)
+    }
+
+    // Makes sure that the blueprint can be rendered with the given number of images:
+    //
+    // * the nodes form a full binary tree, that is every internal node has exactly two children,
+    // * the first node is the only one without a parent and each other node has exactly one
+    //   parent, with no cycles,
+    // * there are at least two leaves, the same as the minimum number of images for a collage,
+    // * leaves point to existing images, each image being used exactly once.
+    //
+    // The first problem found is returned.
+    pub fn validate(&self, image_count: usize) -> Result<(), BlueprintError> {
+        use BlueprintError::*;
+
+        if self.version != BLUEPRINT_VERSION {
+            return Err(UnsupportedVersion(self.version));
+        }
+
+        if self.nodes.is_empty() {
+            return Err(NoNodes);
+        }
+
+        let mut parents: Vec<Vec<usize>> = vec![vec![]; self.nodes.len()];
+        let mut images = vec![false; image_count];
+        let mut leaf_count = 0;
+
+        for (i, node) in self.nodes.iter().enumerate() {
+            match node {
+                BlueprintNode::Internal { split, children } => {
+                    if split != "V" && split != "H" {
+                        return Err(UnknownSplit {
+                            node: i,
+                            split: split.clone(),
+                        });
+                    }
+
+                    if children.len() != 2 {
+                        return Err(WrongNumberOfChildren {
+                            node: i,
+                            count: children.len(),
+                        });
+                    }
+
+                    for &child in children {
+                        match parents.get_mut(child) {
+                            Some(parents) => parents.push(i),
+                            None => return Err(MissingChild { node: i, child }),
+                        }
+                    }
+                }
+                BlueprintNode::Leaf { image, .. } => {
+                    leaf_count += 1;
+
+                    match images.get_mut(*image) {
+                        Some(true) => {
+                            return Err(DuplicateImage {
+                                node: i,
+                                image: *image,
+                            })
+                        }
+                        Some(used) => *used = true,
+                        None if image_count > 0 => {
+                            return Err(MissingImage {
+                                node: i,
+                                image: *image,
+                                image_count,
+                            })
+                        }
+                        // Reported as a wrong number of images below.
+                        None => {}
+                    }
+                }
+            }
+        }
+
+        if let Some(&parent) = parents[0].first() {
+            return Err(RootHasParent { parent });
+        }
+
+        let other_roots: Vec<usize> = (1..self.nodes.len())
+            .filter(|&i| parents[i].is_empty())
+            .collect();
+        if !other_roots.is_empty() {
+            return Err(MultipleRoots { nodes: other_roots });
+        }
+
+        if let Some(node) = (0..self.nodes.len()).find(|&i| parents[i].len() > 1) {
+            return Err(MultipleParents {
+                node,
+                parents: parents[node].clone(),
+            });
+        }
+
+        // Each node has exactly one parent at this point, so anything that isn't reachable from
+        // the root must be going around in circles.
+        let mut reachable = vec![false; self.nodes.len()];
+        let mut to_visit = vec![0];
+
+        while let Some(i) = to_visit.pop() {
+            reachable[i] = true;
+
+            if let BlueprintNode::Internal { children, .. } = &self.nodes[i] {
+                to_visit.extend(children);
+            }
+        }
+
+        if let Some(node) = reachable.iter().position(|reachable| !reachable) {
+            return Err(Cycle { node });
+        }
+
+        if leaf_count < 2 {
+            return Err(TooFewLeaves { leaf_count });
+        }
+
+        if leaf_count != image_count {
+            return Err(WrongNumberOfImages {
+                leaf_count,
+                image_count,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BlueprintRepresentation {
+    Versioned {
+        version: u32,
+        width: u32,
+        height: u32,
+        nodes: Vec<BlueprintNode>,
+    },
+    Legacy {
+        graph_representation: Vec<(String, Vec<usize>)>,
+        width: u32,
+        height: u32,
+    },
+}
+
+impl TryFrom<BlueprintRepresentation> for LayoutBlueprint {
+    type Error = BlueprintError;
+
+    fn try_from(representation: BlueprintRepresentation) -> Result<Self, Self::Error> {
+        match representation {
+            BlueprintRepresentation::Versioned {
+                version,
+                width,
+                height,
+                nodes,
+            } => {
+                if version != BLUEPRINT_VERSION {
+                    return Err(BlueprintError::UnsupportedVersion(version));
+                }
+
+                Ok(LayoutBlueprint::new(width, height, nodes))
+            }
+            BlueprintRepresentation::Legacy {
+                graph_representation,
+                width,
+                height,
+            } => Ok(LayoutBlueprint::from_legacy(
+                width,
+                height,
+                graph_representation,
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use BlueprintNode::*;
+
+    fn internal(split: &str, children: &[usize]) -> BlueprintNode {
+        Internal {
+            split: split.to_string(),
+            children: children.to_vec(),
+        }
+    }
+
+    fn leaf(image: usize) -> BlueprintNode {
+        Leaf { image, hash: None }
+    }
+
+    #[test]
+    fn legacy_blueprints_get_explicit_leaves() {
+        let blueprint: LayoutBlueprint = serde_json::from_value(serde_json::json!({
+            "graph_representation": [["V", [1]], ["H", []]],
+            "width": 10,
+            "height": 20,
+        }))
+        .unwrap();
+
+        assert_eq!(
+            LayoutBlueprint::new(
+                10,
+                20,
+                vec![
+                    internal("V", &[1, 2]),
+                    internal("H", &[3, 4]),
+                    leaf(0),
+                    leaf(1),
+                    leaf(2),
+                ]
+            ),
+            blueprint
+        );
+    }
+
+    #[test]
+    fn versioned_blueprints_round_trip_through_json() {
+        let blueprint = LayoutBlueprint::new(
+            10,
+            20,
+            vec![
+                internal("V", &[1, 2]),
+                leaf(1),
+                Leaf {
+                    image: 0,
+                    hash: Some("abc".to_string()),
+                },
+            ],
+        );
+
+        let json = serde_json::to_value(&blueprint).unwrap();
+
+        assert_eq!(2, json["version"]);
+        assert_eq!(blueprint, serde_json::from_value(json).unwrap());
+    }
+
+    #[test]
+    fn unknown_versions_are_rejected() {
+        let result = serde_json::from_value::<LayoutBlueprint>(serde_json::json!({
+            "version": 3,
+            "width": 10,
+            "height": 20,
+            "nodes": [],
+        }));
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn hashes_take_precedence_over_indices() {
+        let images = vec![
+            RgbaImage::from_pixel(1, 1, image::Rgba([255, 0, 0, 255])),
+            RgbaImage::from_pixel(1, 1, image::Rgba([0, 0, 255, 255])),
+        ];
+        let blueprint = LayoutBlueprint::new(1, 1, vec![internal("V", &[1, 2]), leaf(0), leaf(1)])
+            .with_image_hashes(&images);
+        let reordered_images = vec![images[1].clone(), images[0].clone()];
+
+        assert_eq!(
+            vec![None, Some(1), Some(0)],
+            blueprint.resolve_images(&reordered_images).unwrap()
+        );
+    }
+
+    #[test]
+    fn valid_blueprints_pass_validation() {
+        let blueprint = LayoutBlueprint::new(
+            1,
+            1,
+            vec![
+                internal("V", &[1, 2]),
+                internal("H", &[3, 4]),
+                leaf(2),
+                leaf(0),
+                leaf(1),
+            ],
+        );
+
+        assert_eq!(Ok(()), blueprint.validate(3));
+    }
+
+    #[test]
+    fn internal_nodes_need_a_known_split() {
+        let blueprint = LayoutBlueprint::new(1, 1, vec![internal("X", &[1, 2]), leaf(0), leaf(1)]);
+
+        assert_eq!(
+            Err(BlueprintError::UnknownSplit {
+                node: 0,
+                split: "X".to_string()
+            }),
+            blueprint.validate(2)
+        );
+    }
+
+    #[test]
+    fn internal_nodes_need_two_children() {
+        let blueprint = LayoutBlueprint::new(1, 1, vec![internal("V", &[1]), leaf(0)]);
+
+        assert_eq!(
+            Err(BlueprintError::WrongNumberOfChildren { node: 0, count: 1 }),
+            blueprint.validate(1)
+        );
+
+        let blueprint = LayoutBlueprint::new(
+            1,
+            1,
+            vec![internal("V", &[1, 2, 3]), leaf(0), leaf(1), leaf(2)],
+        );
+
+        assert_eq!(
+            Err(BlueprintError::WrongNumberOfChildren { node: 0, count: 3 }),
+            blueprint.validate(3)
+        );
+    }
+
+    #[test]
+    fn children_need_to_exist() {
+        let blueprint = LayoutBlueprint::new(1, 1, vec![internal("V", &[1, 7]), leaf(0)]);
+
+        assert_eq!(
+            Err(BlueprintError::MissingChild { node: 0, child: 7 }),
+            blueprint.validate(2)
+        );
+    }
+
+    #[test]
+    fn the_first_node_is_the_root() {
+        let blueprint = LayoutBlueprint::new(
+            1,
+            1,
+            vec![
+                internal("V", &[1, 2]),
+                internal("H", &[0, 3]),
+                leaf(0),
+                leaf(1),
+            ],
+        );
+
+        assert_eq!(
+            Err(BlueprintError::RootHasParent { parent: 1 }),
+            blueprint.validate(2)
+        );
+    }
+
+    #[test]
+    fn disconnected_nodes_are_invalid() {
+        let blueprint = LayoutBlueprint::new(
+            1,
+            1,
+            vec![internal("V", &[1, 2]), leaf(0), leaf(1), leaf(2)],
+        );
+
+        assert_eq!(
+            Err(BlueprintError::MultipleRoots { nodes: vec![3] }),
+            blueprint.validate(3)
+        );
+    }
+
+    #[test]
+    fn nodes_can_have_only_one_parent() {
+        let blueprint = LayoutBlueprint::new(
+            1,
+            1,
+            vec![
+                internal("V", &[1, 2]),
+                internal("H", &[2, 3]),
+                leaf(0),
+                leaf(1),
+            ],
+        );
+
+        assert_eq!(
+            Err(BlueprintError::MultipleParents {
+                node: 2,
+                parents: vec![0, 1]
+            }),
+            blueprint.validate(2)
+        );
+    }
+
+    #[test]
+    fn cycles_are_invalid() {
+        let blueprint = LayoutBlueprint::new(
+            1,
+            1,
+            vec![
+                internal("V", &[1, 2]),
+                leaf(0),
+                leaf(1),
+                internal("H", &[4, 5]),
+                internal("H", &[3, 6]),
+                leaf(2),
+                leaf(3),
+            ],
+        );
+
+        assert_eq!(
+            Err(BlueprintError::Cycle { node: 3 }),
+            blueprint.validate(4)
+        );
+    }
+
+    #[test]
+    fn leaves_need_to_point_to_existing_images() {
+        let blueprint = LayoutBlueprint::new(1, 1, vec![internal("V", &[1, 2]), leaf(0), leaf(5)]);
+
+        assert_eq!(
+            Err(BlueprintError::MissingImage {
+                node: 2,
+                image: 5,
+                image_count: 2
+            }),
+            blueprint.validate(2)
+        );
+    }
+
+    #[test]
+    fn images_can_be_used_only_once() {
+        let blueprint = LayoutBlueprint::new(1, 1, vec![internal("V", &[1, 2]), leaf(0), leaf(0)]);
+
+        assert_eq!(
+            Err(BlueprintError::DuplicateImage { node: 2, image: 0 }),
+            blueprint.validate(2)
+        );
+    }
+
+    #[test]
+    fn the_root_cannot_be_a_leaf() {
+        let blueprint = LayoutBlueprint::new(1, 1, vec![leaf(0)]);
+
+        assert_eq!(
+            Err(BlueprintError::TooFewLeaves { leaf_count: 1 }),
+            blueprint.validate(1)
+        );
+    }
+
+    #[test]
+    fn number_of_leaves_needs_to_match_number_of_images() {
+        let blueprint = LayoutBlueprint::new(1, 1, vec![internal("V", &[1, 2]), leaf(0), leaf(1)]);
+
+        assert_eq!(
+            Err(BlueprintError::WrongNumberOfImages {
+                leaf_count: 2,
+                image_count: 3
+            }),
+            blueprint.validate(3)
+        );
+    }
+
+    #[test]
+    fn index_leaves_claim_their_images_before_hash_leaves() {
+        let images = vec![
+            RgbaImage::from_pixel(1, 1, image::Rgba([255, 0, 0, 255])),
+            RgbaImage::from_pixel(1, 1, image::Rgba([0, 255, 0, 255])),
+            RgbaImage::from_pixel(1, 1, image::Rgba([0, 0, 255, 255])),
+        ];
+        let blueprint = LayoutBlueprint::new(
+            1,
+            1,
+            vec![
+                internal("V", &[1, 2]),
+                Leaf {
+                    image: 0,
+                    hash: Some(images[2].content_hash()),
+                },
+                internal("H", &[3, 4]),
+                leaf(1),
+                Leaf {
+                    image: 2,
+                    hash: Some(images[0].content_hash()),
+                },
+            ],
+        );
+
+        assert_eq!(
+            vec![None, Some(2), None, Some(1), Some(0)],
+            blueprint.resolve_images(&images).unwrap()
+        );
+    }
+
+    #[test]
+    fn images_claimed_by_both_an_index_and_a_hash_are_rejected() {
+        let images = vec![
+            RgbaImage::from_pixel(1, 1, image::Rgba([255, 0, 0, 255])),
+            RgbaImage::from_pixel(1, 1, image::Rgba([0, 0, 255, 255])),
+        ];
+        let blueprint = LayoutBlueprint::new(
+            1,
+            1,
+            vec![
+                internal("V", &[1, 2]),
+                leaf(0),
+                Leaf {
+                    image: 1,
+                    hash: Some(images[0].content_hash()),
+                },
+            ],
+        );
+
+        assert_eq!(
+            Err(BlueprintError::DuplicateImage { node: 2, image: 0 }),
+            blueprint.resolve_images(&images)
+        );
+    }
+
+    #[test]
+    fn unknown_hashes_are_reported() {
+        let images = vec![RgbaImage::new(1, 1), RgbaImage::new(2, 1)];
+        let blueprint = LayoutBlueprint::new(
+            1,
+            1,
+            vec![
+                internal("V", &[1, 2]),
+                leaf(0),
+                Leaf {
+                    image: 1,
+                    hash: Some("abc".to_string()),
+                },
+            ],
+        );
+
+        assert_eq!(
+            Err(BlueprintError::UnknownImageHash {
+                node: 2,
+                hash: "abc".to_string()
+            }),
+            blueprint.resolve_images(&images)
+        );
+    }
+}
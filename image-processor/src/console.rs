@@ -0,0 +1,40 @@
+// A `log` backend which writes to the browser console.
+//
+// Calling any web_sys function outside of wasm aborts the process, so this module is compiled only
+// for wasm targets with the `console` feature enabled. Native users of the crate can plug in any
+// other `log` implementation instead.
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use wasm_bindgen::JsValue;
+use web_sys::console;
+
+struct ConsoleLogger;
+
+static LOGGER: ConsoleLogger = ConsoleLogger;
+
+impl Log for ConsoleLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let message: JsValue = format!("{}", record.args()).into();
+
+        match record.level() {
+            Level::Error => console::error_1(&message),
+            Level::Warn => console::warn_1(&message),
+            Level::Info => console::info_1(&message),
+            // console.debug is hidden by default in most browsers.
+            Level::Debug | Level::Trace => console::log_1(&message),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+// Does nothing if some other logger has already been set.
+pub fn init(level: LevelFilter) {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level);
+    }
+}
@@ -0,0 +1,392 @@
+// How good a layout is. The genetic algorithm looks for the layout with the lowest cost, so the
+// cost function decides what the collage ends up looking like.
+//
+// The default cost combines two terms: how far the images are from their original size and how
+// much of the canvas is left empty. Other terms can be added and weighted against each other, e.g.
+// to favour images of similar size or a particular shape of the collage. From JS, the terms are
+// passed as a list:
+//
+//     cost: [
+//       {term: "scale_deviation", weight: 2},
+//       {term: "canvas_coverage"},
+//       {term: "aspect_ratio", target: "4:5", weight: 0.5},
+//     ]
+//
+// The weight defaults to 1. Anything else implementing CostFunction can be used from Rust.
+
+use crate::error::CollageError;
+use crate::layout::{Dimensions, Layout};
+use crate::options::AspectRatio;
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::fmt::Debug;
+
+// Smaller value is better. A perfect layout has zero cost and the search stops once it finds one,
+// so implementations should never go below zero.
+pub trait CostFunction: Debug + Send + Sync {
+    fn cost(&self, layout: &Layout) -> f64;
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "term", rename_all = "snake_case")]
+pub enum CostTerm {
+    // Relative difference between the area of each image in the collage and its original area,
+    // see `Layout::reference_scale`. Summed over all images and multiplied by their number, so
+    // that it outweighs the coverage more and more as the collage grows.
+    ScaleDeviation,
+    // Share of the canvas that isn't covered by the images.
+    CanvasCoverage,
+    // Coefficient of variation of the areas of the images, zero if all of them are equally big.
+    SizeUniformity,
+    // How far the shape of the collage is from the target. Twice as wide costs the same as twice
+    // as tall.
+    AspectRatio { target: AspectRatio },
+    // Relative difference between the share of the collage taken by each image and the share
+    // given by its weight. Images without a weight get a weight of 1.
+    Importance { weights: Vec<f64> },
+}
+
+impl CostFunction for CostTerm {
+    fn cost(&self, layout: &Layout) -> f64 {
+        match self {
+            CostTerm::ScaleDeviation => scale_deviation(layout),
+            CostTerm::CanvasCoverage => canvas_coverage(layout),
+            CostTerm::SizeUniformity => size_uniformity(layout),
+            CostTerm::AspectRatio { target } => aspect_ratio_deviation(layout, target.value()),
+            CostTerm::Importance { weights } => importance_deviation(layout, weights),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct WeightedTerm {
+    #[serde(default = "default_weight")]
+    pub weight: f64,
+    #[serde(flatten)]
+    pub term: CostTerm,
+}
+
+fn default_weight() -> f64 {
+    1.0
+}
+
+// Sum of the terms multiplied by their weights.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "Vec<WeightedTerm>")]
+pub struct WeightedCost {
+    pub terms: Vec<WeightedTerm>,
+}
+
+impl WeightedCost {
+    pub fn new(terms: Vec<WeightedTerm>) -> Result<Self, CollageError> {
+        let cost = WeightedCost { terms };
+
+        cost.validate()?;
+
+        Ok(cost)
+    }
+
+    pub fn validate(&self) -> Result<(), CollageError> {
+        // Every layout would be perfect, so the search would stop right away.
+        if self.terms.is_empty() {
+            return Err(CollageError::InvalidOptions(
+                "The cost function needs at least one term".to_string(),
+            ));
+        }
+
+        for WeightedTerm { weight, term } in &self.terms {
+            if !(*weight >= 0.0 && weight.is_finite()) {
+                return Err(CollageError::InvalidOptions(format!(
+                    "Cost weights must be non-negative numbers, got {} for {:?}",
+                    weight, term
+                )));
+            }
+
+            if let CostTerm::Importance { weights } = term {
+                if !weights
+                    .iter()
+                    .all(|weight| *weight > 0.0 && weight.is_finite())
+                {
+                    return Err(CollageError::InvalidOptions(format!(
+                        "Image weights must be positive numbers, got {:?}",
+                        weights
+                    )));
+                }
+            }
+        }
+
+        // Same as having no terms at all.
+        if self.terms.iter().all(|term| term.weight == 0.0) {
+            return Err(CollageError::InvalidOptions(
+                "The cost function needs at least one term with a weight greater than zero"
+                    .to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for WeightedCost {
+    fn default() -> Self {
+        WeightedCost {
+            terms: vec![
+                WeightedTerm {
+                    weight: 1.0,
+                    term: CostTerm::ScaleDeviation,
+                },
+                WeightedTerm {
+                    weight: 1.0,
+                    term: CostTerm::CanvasCoverage,
+                },
+            ],
+        }
+    }
+}
+
+impl TryFrom<Vec<WeightedTerm>> for WeightedCost {
+    type Error = CollageError;
+
+    fn try_from(terms: Vec<WeightedTerm>) -> Result<Self, Self::Error> {
+        WeightedCost::new(terms)
+    }
+}
+
+impl CostFunction for WeightedCost {
+    fn cost(&self, layout: &Layout) -> f64 {
+        self.terms
+            .iter()
+            .map(|WeightedTerm { weight, term }| weight * term.cost(layout))
+            .sum()
+    }
+}
+
+// Previous implementation of the cost function, useful for comparing new results to old ones.
+#[derive(Debug, Clone, Copy)]
+pub struct LegacyCost;
+
+impl CostFunction for LegacyCost {
+    fn cost(&self, layout: &Layout) -> f64 {
+        let number_of_images = layout.leaf_nodes().count() as f64;
+
+        sum_of_scale_deviations(layout) + number_of_images * canvas_coverage(layout)
+    }
+}
+
+fn scale_deviation(layout: &Layout) -> f64 {
+    let number_of_images = layout.leaf_nodes().count() as f64;
+
+    number_of_images * sum_of_scale_deviations(layout)
+}
+
+fn sum_of_scale_deviations(layout: &Layout) -> f64 {
+    let reference_scale = layout.reference_scale();
+
+    layout
+        .leaf_nodes()
+        .map(|leaf_node| {
+            let original_image_size =
+                Dimensions::from_tuple(leaf_node.image().unwrap().dimensions()).size() as f64;
+            let reference_image_size = original_image_size * reference_scale;
+            let scaled_image_size = leaf_node.dimensions().size() as f64;
+
+            (scaled_image_size - reference_image_size).abs() / reference_image_size
+        })
+        .sum::<f64>()
+}
+
+fn canvas_coverage(layout: &Layout) -> f64 {
+    1.0 - layout
+        .leaf_nodes()
+        .map(|leaf_node| {
+            leaf_node.dimensions().size() as f64 / layout.canvas_dimensions.size() as f64
+        })
+        .sum::<f64>()
+}
+
+fn size_uniformity(layout: &Layout) -> f64 {
+    let sizes: Vec<f64> = layout
+        .leaf_nodes()
+        .map(|leaf_node| leaf_node.dimensions().size() as f64)
+        .collect();
+    let mean = sizes.iter().sum::<f64>() / sizes.len() as f64;
+
+    // Images squashed down to nothing are as far from uniform as it gets.
+    if mean == 0.0 {
+        return 1.0;
+    }
+
+    let variance = sizes
+        .iter()
+        .map(|size| (size - mean) * (size - mean))
+        .sum::<f64>()
+        / sizes.len() as f64;
+
+    variance.sqrt() / mean
+}
+
+fn aspect_ratio_deviation(layout: &Layout, target: f64) -> f64 {
+    let (width, height) = layout.dimensions();
+
+    if width == 0 || height == 0 {
+        return f64::MAX;
+    }
+
+    let aspect_ratio = width as f64 / height as f64;
+
+    (aspect_ratio / target).max(target / aspect_ratio) - 1.0
+}
+
+fn importance_deviation(layout: &Layout, weights: &[f64]) -> f64 {
+    let weight_of = |image_index: Option<usize>| {
+        image_index
+            .and_then(|image_index| weights.get(image_index))
+            .copied()
+            .unwrap_or(1.0)
+    };
+    let total_weight: f64 = layout
+        .leaf_nodes()
+        .map(|leaf_node| weight_of(leaf_node.image_index()))
+        .sum();
+    let total_size: f64 = layout
+        .leaf_nodes()
+        .map(|leaf_node| leaf_node.dimensions().size() as f64)
+        .sum();
+
+    if total_size == 0.0 {
+        return layout.leaf_nodes().count() as f64;
+    }
+
+    layout
+        .leaf_nodes()
+        .map(|leaf_node| {
+            let expected_share = weight_of(leaf_node.image_index()) / total_weight;
+            let share = leaf_node.dimensions().size() as f64 / total_size;
+
+            (share - expected_share).abs() / expected_share
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blueprint::LayoutBlueprint;
+    use image::RgbaImage;
+
+    fn layout<'a>(expression: &str, canvas: (u32, u32), images: &'a [RgbaImage]) -> Layout<'a> {
+        let blueprint = LayoutBlueprint::from_expression(expression, canvas.0, canvas.1).unwrap();
+
+        Layout::from_blueprint(&blueprint, images).unwrap()
+    }
+
+    fn weighted(term: CostTerm) -> WeightedCost {
+        WeightedCost::new(vec![WeightedTerm { weight: 1.0, term }]).unwrap()
+    }
+
+    #[test]
+    fn perfect_layout_costs_nothing() {
+        let images = vec![RgbaImage::new(100, 100), RgbaImage::new(100, 100)];
+        let layout = layout("V(0,1)", (200, 100), &images);
+
+        for term in [
+            CostTerm::ScaleDeviation,
+            CostTerm::CanvasCoverage,
+            CostTerm::SizeUniformity,
+            CostTerm::AspectRatio {
+                target: AspectRatio::new(2.0).unwrap(),
+            },
+            CostTerm::Importance { weights: vec![] },
+        ] {
+            assert_eq!(0.0, term.cost(&layout), "{:?}", term);
+        }
+    }
+
+    #[test]
+    fn default_cost_matches_the_layout_cost() {
+        let images = vec![RgbaImage::new(40, 30), RgbaImage::new(30, 40)];
+        let layout = layout("H(0,1)", (50, 90), &images);
+
+        assert!(layout.cost() > 0.0);
+        assert_eq!(layout.cost(), WeightedCost::default().cost(&layout));
+    }
+
+    #[test]
+    fn aspect_ratio_deviation_is_symmetric() {
+        let images = vec![RgbaImage::new(100, 100), RgbaImage::new(100, 100)];
+        let wide = layout("V(0,1)", (200, 100), &images);
+        let tall = layout("H(0,1)", (100, 200), &images);
+        let square = weighted(CostTerm::AspectRatio {
+            target: AspectRatio::new(1.0).unwrap(),
+        });
+
+        assert_eq!(1.0, square.cost(&wide));
+        assert_eq!(square.cost(&wide), square.cost(&tall));
+    }
+
+    #[test]
+    fn size_uniformity_penalises_images_of_different_sizes() {
+        let images = vec![RgbaImage::new(100, 100), RgbaImage::new(100, 100)];
+        let uneven_images = vec![RgbaImage::new(100, 100), RgbaImage::new(100, 300)];
+        let uniformity = weighted(CostTerm::SizeUniformity);
+
+        assert_eq!(0.0, uniformity.cost(&layout("V(0,1)", (200, 100), &images)));
+        assert_eq!(
+            0.5,
+            uniformity.cost(&layout("V(0,1)", (200, 300), &uneven_images))
+        );
+    }
+
+    #[test]
+    fn importance_prefers_bigger_heavier_images() {
+        let images = vec![RgbaImage::new(100, 100), RgbaImage::new(100, 300)];
+        let layout = layout("H(0,1)", (100, 400), &images);
+
+        // The second image takes three quarters of the collage.
+        let importance = |weights: Vec<f64>| weighted(CostTerm::Importance { weights });
+
+        assert_eq!(0.0, importance(vec![1.0, 3.0]).cost(&layout));
+        assert!(importance(vec![1.0, 1.0]).cost(&layout) > 0.0);
+    }
+
+    #[test]
+    fn terms_deserialize_with_a_default_weight() {
+        let cost: WeightedCost = serde_json::from_value(serde_json::json!([
+            {"term": "scale_deviation", "weight": 2},
+            {"term": "aspect_ratio", "target": "4:5"},
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            vec![
+                WeightedTerm {
+                    weight: 2.0,
+                    term: CostTerm::ScaleDeviation
+                },
+                WeightedTerm {
+                    weight: 1.0,
+                    term: CostTerm::AspectRatio {
+                        target: AspectRatio::new(0.8).unwrap()
+                    }
+                },
+            ],
+            cost.terms
+        );
+    }
+
+    #[test]
+    fn invalid_cost_functions_are_rejected() {
+        let parse = |json| serde_json::from_value::<WeightedCost>(json);
+
+        assert!(parse(serde_json::json!([])).is_err());
+        assert!(parse(serde_json::json!([{"term": "unknown"}])).is_err());
+        assert!(parse(serde_json::json!([{"term": "canvas_coverage", "weight": -1}])).is_err());
+        assert!(parse(serde_json::json!([{"term": "importance", "weights": [1, 0]}])).is_err());
+        assert!(parse(serde_json::json!([{"term": "scale_deviation", "weight": 0}])).is_err());
+        assert!(parse(serde_json::json!([
+            {"term": "scale_deviation", "weight": 0},
+            {"term": "canvas_coverage", "weight": 0.5},
+        ]))
+        .is_ok());
+    }
+}
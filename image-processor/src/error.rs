@@ -0,0 +1,215 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum CollageError {
+    // The input isn't a valid image in a format we support.
+    Decode(image::ImageError),
+    // The input looks like an image, but in a format which wasn't compiled in or which the image
+    // crate can't handle.
+    UnsupportedFormat(String),
+    // A collage needs at least two images. The field holds the number of images received.
+    TooFewImages(usize),
+    InvalidBlueprint(BlueprintError),
+    InvalidOptions(String),
+    Encode(String),
+    // The genetic algorithm didn't produce a final result.
+    LayoutGeneration(String),
+}
+
+impl CollageError {
+    // The image crate reports unsupported formats as one of its own error kinds, so decoding
+    // errors need to be sorted out before they're wrapped.
+    pub fn from_decoding_error(err: image::ImageError) -> Self {
+        match err {
+            image::ImageError::Unsupported(err) => CollageError::UnsupportedFormat(err.to_string()),
+            err => CollageError::Decode(err),
+        }
+    }
+}
+
+impl fmt::Display for CollageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use CollageError::*;
+
+        match self {
+            Decode(err) => write!(f, "Could not decode the image: {}", err),
+            UnsupportedFormat(reason) => write!(f, "Unsupported image format: {}", reason),
+            TooFewImages(count) => {
+                write!(f, "A collage needs at least two images, received {}", count)
+            }
+            InvalidBlueprint(reason) => write!(f, "Invalid layout blueprint: {}", reason),
+            InvalidOptions(reason) => write!(f, "Invalid options: {}", reason),
+            Encode(reason) => write!(f, "Could not encode the collage: {}", reason),
+            LayoutGeneration(reason) => write!(f, "Could not generate a layout: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for CollageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CollageError::Decode(err) => Some(err),
+            CollageError::InvalidBlueprint(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+// Everything that can be wrong with a blueprint. Nodes are referred to by their position in the
+// list of nodes, so that the user can find the one that needs fixing.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlueprintError {
+    // The blueprint couldn't be deserialized at all.
+    Malformed(String),
+    // A slicing tree expression such as `V(0,H(1,2))` couldn't be parsed. The position is a byte
+    // offset into the expression.
+    InvalidExpression {
+        position: usize,
+        reason: String,
+    },
+    UnsupportedVersion(u32),
+    NoNodes,
+    UnknownSplit {
+        node: usize,
+        split: String,
+    },
+    // Internal nodes need exactly two children.
+    WrongNumberOfChildren {
+        node: usize,
+        count: usize,
+    },
+    MissingChild {
+        node: usize,
+        child: usize,
+    },
+    // The first node is the root, so nothing can point to it.
+    RootHasParent {
+        parent: usize,
+    },
+    // Nodes other than the first one which have no parent.
+    MultipleRoots {
+        nodes: Vec<usize>,
+    },
+    MultipleParents {
+        node: usize,
+        parents: Vec<usize>,
+    },
+    // A node which is not reachable from the root even though it has a parent, which can only
+    // happen if it's a part of a cycle.
+    Cycle {
+        node: usize,
+    },
+    MissingImage {
+        node: usize,
+        image: usize,
+        image_count: usize,
+    },
+    DuplicateImage {
+        node: usize,
+        image: usize,
+    },
+    UnknownImageHash {
+        node: usize,
+        hash: String,
+    },
+    // A collage needs at least two images, so the root can't be a leaf.
+    TooFewLeaves {
+        leaf_count: usize,
+    },
+    // A full binary tree with N leaves has N - 1 internal nodes, so it needs exactly N images.
+    WrongNumberOfImages {
+        leaf_count: usize,
+        image_count: usize,
+    },
+}
+
+impl fmt::Display for BlueprintError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use BlueprintError::*;
+
+        match self {
+            Malformed(reason) => write!(f, "{}", reason),
+            InvalidExpression { position, reason } => {
+                write!(f, "invalid expression at position {}: {}", position, reason)
+            }
+            UnsupportedVersion(version) => write!(f, "unsupported version {}", version),
+            NoNodes => write!(f, "the blueprint has no nodes"),
+            UnknownSplit { node, split } => {
+                write!(f, "node {} has an unknown split {:?}", node, split)
+            }
+            WrongNumberOfChildren { node, count } => {
+                write!(f, "node {} has {} children instead of two", node, count)
+            }
+            MissingChild { node, child } => {
+                write!(
+                    f,
+                    "node {} points to node {}, which doesn't exist",
+                    node, child
+                )
+            }
+            RootHasParent { parent } => {
+                write!(f, "node {} points to the root node 0", parent)
+            }
+            MultipleRoots { nodes } => write!(
+                f,
+                "nodes {:?} have no parent, only the root node 0 can have no parent",
+                nodes
+            ),
+            MultipleParents { node, parents } => {
+                write!(f, "node {} has more than one parent: {:?}", node, parents)
+            }
+            Cycle { node } => write!(f, "node {} is a part of a cycle", node),
+            MissingImage {
+                node,
+                image,
+                image_count,
+            } => write!(
+                f,
+                "node {} points to image {}, but there are only {} images",
+                node, image, image_count
+            ),
+            DuplicateImage { node, image } => {
+                write!(
+                    f,
+                    "node {} points to image {}, which is already used",
+                    node, image
+                )
+            }
+            UnknownImageHash { node, hash } => {
+                write!(
+                    f,
+                    "none of the images matches the hash {:?} of node {}",
+                    hash, node
+                )
+            }
+            TooFewLeaves { leaf_count } => write!(
+                f,
+                "the blueprint has {} leaves, but a collage needs at least two",
+                leaf_count
+            ),
+            WrongNumberOfImages {
+                leaf_count,
+                image_count,
+            } => write!(
+                f,
+                "the blueprint has {} leaves, but received {} images",
+                leaf_count, image_count
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BlueprintError {}
+
+impl From<BlueprintError> for CollageError {
+    fn from(err: BlueprintError) -> Self {
+        CollageError::InvalidBlueprint(err)
+    }
+}
+
+// Lets the wasm entry points return a Result, which wasm-bindgen turns into a thrown JS error.
+impl From<CollageError> for wasm_bindgen::JsValue {
+    fn from(err: CollageError) -> Self {
+        js_sys::Error::new(&err.to_string()).into()
+    }
+}
@@ -0,0 +1,345 @@
+// Finds the best layout for a handful of images by trying all of them, rather than hoping that the
+// genetic algorithm stumbles upon it.
+//
+// Many slicing trees give exactly the same sizes to all images. The children of a split can be
+// swapped and a row of images, e.g. V(0,V(1,2)), can be split up in any order. Trees are therefore
+// enumerated with splits of any number of children, alternating between vertical and horizontal
+// ones, which leaves 2 * 236 of them for 5 images, 2 * 2752 for 6 and 2 * 39208 for 7, which is
+// where it stops being quick enough.
+//
+// For each tree, the canvas is fitted tightly around it, as any extra space only adds to the cost.
+// The scale deviation is then piecewise linear in the area of the canvas, with its minimum at a
+// scale at which one of the images has exactly its reference size, so those are the only scales
+// worth trying. The other built-in terms of the cost don't depend on the scale. The result is
+// therefore the layout with the lowest cost, give or take rounding to whole pixels and the
+// gutters, which don't scale along with the images. Cost functions which depend on where the
+// images are or on the size of the collage in other ways get no such guarantee.
+
+use crate::blueprint::{BlueprintNode, LayoutBlueprint};
+use crate::cost::CostFunction;
+use crate::error::CollageError;
+use crate::layout::{Dimensions, Layout};
+use crate::options::LayoutOptions;
+use image::RgbaImage;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+pub const MAX_IMAGES: usize = 7;
+
+// Big enough for the images to be measured precisely, small enough for the area to fit in u32.
+const MEASURING_CANVAS: Dimensions = Dimensions {
+    width: 20_000,
+    height: 20_000,
+};
+
+pub(crate) enum Tree {
+    // Two or more children, none of which are split in the same direction.
+    Split(&'static str, Vec<Rc<Tree>>),
+    Image(usize),
+}
+
+// Trees of each set of images, with the set as a bit mask, by the direction of the root split.
+type Subtrees = HashMap<(u32, &'static str), Vec<Rc<Tree>>>;
+
+pub fn generate_layout<'a>(
+    images: &'a [RgbaImage],
+    options: &LayoutOptions,
+    cost_function: &dyn CostFunction,
+) -> Result<Layout<'a>, CollageError> {
+    if images.len() < 2 {
+        return Err(CollageError::TooFewImages(images.len()));
+    }
+
+    if images.len() > MAX_IMAGES {
+        return Err(CollageError::InvalidOptions(format!(
+            "The exact search supports at most {} images, received {}",
+            MAX_IMAGES,
+            images.len()
+        )));
+    }
+
+    let all_images = (1 << images.len()) - 1;
+    let mut subtrees = Subtrees::new();
+    let mut best: Option<(Layout<'a>, f64)> = None;
+
+    for split in ["V", "H"] {
+        for tree in trees(all_images, split, &mut subtrees) {
+            let blueprint = LayoutBlueprint::new(1, 1, to_nodes(&tree));
+            let layout = Layout::from_blueprint(&blueprint, images)?;
+            let (layout, cost) = fit_canvas(layout, options, cost_function);
+
+            if best.as_ref().is_none_or(|(_, best_cost)| cost < *best_cost) {
+                best = Some((layout, cost));
+            }
+        }
+    }
+
+    Ok(best.expect("No layouts to choose from").0)
+}
+
+fn trees(images: u32, split: &'static str, subtrees: &mut Subtrees) -> Vec<Rc<Tree>> {
+    if let Some(trees) = subtrees.get(&(images, split)) {
+        return trees.clone();
+    }
+
+    let other_split = if split == "V" { "H" } else { "V" };
+    let mut trees = vec![];
+
+    for partition in partitions(images)
+        .into_iter()
+        .filter(|parts| parts.len() > 1)
+    {
+        let mut children_choices: Vec<Vec<Rc<Tree>>> = vec![vec![]];
+
+        for part in partition {
+            let part_trees = if part.count_ones() == 1 {
+                vec![Rc::new(Tree::Image(part.trailing_zeros() as usize))]
+            } else {
+                self::trees(part, other_split, subtrees)
+            };
+
+            children_choices = children_choices
+                .iter()
+                .flat_map(|children| {
+                    part_trees.iter().map(move |tree| {
+                        let mut children = children.clone();
+                        children.push(tree.clone());
+                        children
+                    })
+                })
+                .collect();
+        }
+
+        trees.extend(
+            children_choices
+                .into_iter()
+                .map(|children| Rc::new(Tree::Split(split, children))),
+        );
+    }
+
+    subtrees.insert((images, split), trees.clone());
+
+    trees
+}
+
+// All ways to split the set into non-empty parts, regardless of their order.
+fn partitions(set: u32) -> Vec<Vec<u32>> {
+    if set == 0 {
+        return vec![vec![]];
+    }
+
+    // The part with the lowest element comes first, so each partition is only listed once.
+    let lowest = set & set.wrapping_neg();
+
+    (1..=set)
+        .filter(|part| part & !set == 0 && part & lowest != 0)
+        .flat_map(|part| {
+            partitions(set & !part).into_iter().map(move |mut rest| {
+                rest.insert(0, part);
+                rest
+            })
+        })
+        .collect()
+}
+
+// Fits the canvas tightly around the layout. Of the canvases at which one of the images has its
+// reference size, the one with the lowest cost is picked.
+pub(crate) fn fit_canvas<'a>(
+    layout: Layout<'a>,
+    options: &LayoutOptions,
+    cost_function: &dyn CostFunction,
+) -> (Layout<'a>, f64) {
+    let layout = layout.with_options(*options);
+    let reference_scale = layout.reference_scale();
+
+    // Measured without the limits, as they'd shrink the canvas. Images are cropped to the shape of
+    // the canvas, so cropping only makes sense for a locked aspect ratio, and the measuring canvas
+    // has to have that shape then.
+    let mut layout = layout.with_options(LayoutOptions {
+        max_width: None,
+        max_height: None,
+        max_megapixels: None,
+        aspect_ratio: None,
+        crop_tolerance: if options.aspect_ratio.is_some() {
+            options.crop_tolerance
+        } else {
+            0.0
+        },
+        ..*options
+    });
+    layout.canvas_dimensions = measuring_canvas(options);
+
+    let margins = options.margin * 2;
+    let (width, height) = layout.dimensions();
+    let (width, height) = ((width - margins) as f64, (height - margins) as f64);
+    let scales: Vec<f64> = layout
+        .leaf_nodes()
+        .map(|leaf_node| {
+            let (original_width, original_height) = leaf_node.image().unwrap().dimensions();
+            let reference_size = original_width as f64 * original_height as f64 * reference_scale;
+
+            (reference_size / leaf_node.dimensions().size() as f64).sqrt()
+        })
+        // Images squashed down to nothing can't be measured.
+        .filter(|scale| scale.is_finite())
+        .collect();
+
+    let mut layout = layout.with_options(*options);
+    let mut best: Option<(Dimensions, f64)> = None;
+
+    for scale in scales {
+        let mut canvas_dimensions = Dimensions {
+            width: ((width * scale).round() as u32).max(1) + margins,
+            height: ((height * scale).round() as u32).max(1) + margins,
+        };
+
+        // The height is derived from the width, so the width has to be big enough for the tree to
+        // fit in the height as well.
+        if let Some(aspect_ratio) = options.aspect_ratio {
+            canvas_dimensions.width = canvas_dimensions
+                .width
+                .max((canvas_dimensions.height as f64 * aspect_ratio.value()).round() as u32);
+        }
+
+        layout.canvas_dimensions = canvas_dimensions;
+        layout = layout.with_options(*options);
+
+        let cost = cost_function.cost(&layout);
+
+        if best.is_none_or(|(_, best_cost)| cost < best_cost) {
+            best = Some((layout.canvas_dimensions, cost));
+        }
+    }
+
+    let (canvas_dimensions, cost) = best.unwrap_or((MEASURING_CANVAS, f64::INFINITY));
+
+    layout.canvas_dimensions = canvas_dimensions;
+
+    (layout.with_options(*options), cost)
+}
+
+fn measuring_canvas(options: &LayoutOptions) -> Dimensions {
+    let side = |side: f64| (side.round() as u32).max(1);
+
+    match options
+        .aspect_ratio
+        .map(|aspect_ratio| aspect_ratio.value())
+    {
+        Some(aspect_ratio) if aspect_ratio >= 1.0 => Dimensions {
+            width: MEASURING_CANVAS.width,
+            height: side(MEASURING_CANVAS.height as f64 / aspect_ratio),
+        },
+        Some(aspect_ratio) => Dimensions {
+            width: side(MEASURING_CANVAS.width as f64 * aspect_ratio),
+            height: MEASURING_CANVAS.height,
+        },
+        None => MEASURING_CANVAS,
+    }
+}
+
+// The root comes first, as blueprints expect.
+pub(crate) fn to_nodes(tree: &Tree) -> Vec<BlueprintNode> {
+    let mut nodes = vec![];
+
+    push_node(tree, &mut nodes);
+
+    nodes
+}
+
+fn push_node(tree: &Tree, nodes: &mut Vec<BlueprintNode>) -> usize {
+    match tree {
+        Tree::Split(split, children) => push_split(split, children, nodes),
+        Tree::Image(image) => {
+            nodes.push(BlueprintNode::Leaf {
+                image: *image,
+                hash: None,
+            });
+            nodes.len() - 1
+        }
+    }
+}
+
+// Splits with more than two children become a chain of binary ones, e.g. V(0,V(1,2)).
+fn push_split(split: &str, children: &[Rc<Tree>], nodes: &mut Vec<BlueprintNode>) -> usize {
+    if let [child] = children {
+        return push_node(child, nodes);
+    }
+
+    let index = nodes.len();
+
+    nodes.push(BlueprintNode::Internal {
+        split: split.to_string(),
+        children: vec![],
+    });
+
+    let left = push_node(&children[0], nodes);
+    let right = push_split(split, &children[1..], nodes);
+
+    nodes[index] = BlueprintNode::Internal {
+        split: split.to_string(),
+        children: vec![left, right],
+    };
+
+    index
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cost::WeightedCost;
+    use crate::test_utils::{images, FIVE_IMAGES};
+
+    #[test]
+    fn every_distinct_tree_is_tried_once() {
+        let mut subtrees = HashMap::new();
+
+        let mut count = |images| trees(images, "V", &mut subtrees).len();
+
+        // Number of series-reduced trees with labelled leaves, see the comment at the top.
+        assert_eq!(1, count(0b11));
+        assert_eq!(4, count(0b111));
+        assert_eq!(26, count(0b1111));
+        assert_eq!(236, count(0b11111));
+    }
+
+    #[test]
+    fn images_which_fit_together_perfectly_cost_nothing() {
+        // A square next to or on top of two halves of a square.
+        let images = images(&[(100, 100), (50, 100), (50, 100)]);
+        let layout =
+            generate_layout(&images, &LayoutOptions::default(), &WeightedCost::default()).unwrap();
+
+        assert_eq!(0.0, layout.cost());
+        assert!([(200, 100), (100, 200)].contains(&layout.dimensions()));
+    }
+
+    #[test]
+    fn exact_layout_is_at_least_as_good_as_any_random_one() {
+        use rand_core::SeedableRng;
+
+        let images = images(&FIVE_IMAGES);
+        let options = LayoutOptions::default();
+        let exact_cost = generate_layout(&images, &options, &WeightedCost::default())
+            .unwrap()
+            .cost();
+        let mut rng = rand_pcg::Pcg64::seed_from_u64(1);
+
+        for _ in 0..200 {
+            let layout = Layout::new(&images, &mut rng)
+                .unwrap()
+                .with_options(options);
+
+            assert!(exact_cost <= layout.cost());
+        }
+    }
+
+    #[test]
+    fn too_many_images_are_rejected() {
+        let images = vec![RgbaImage::new(1, 1); MAX_IMAGES + 1];
+
+        assert!(matches!(
+            generate_layout(&images, &LayoutOptions::default(), &WeightedCost::default()),
+            Err(CollageError::InvalidOptions(_))
+        ));
+    }
+}
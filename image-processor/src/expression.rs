@@ -0,0 +1,298 @@
+// A compact textual notation for slicing trees, e.g.
+//
+//     V(H(0,1),V(2,H(3,4)))
+//
+// `V(left,right)` puts the children side by side, `H(top,bottom)` stacks them on top of each
+// other and numbers are image indices, the same as in blueprint leaves. Whitespace between
+// tokens is ignored.
+//
+// Parsed expressions are turned into blueprint nodes in the same order that `Layout::to_blueprint`
+// uses, so that an expression, its blueprint and a layout made from that blueprint can be
+// converted back and forth without any changes.
+
+use crate::blueprint::BlueprintNode;
+use crate::error::BlueprintError;
+use std::collections::VecDeque;
+
+// Splits are parsed recursively, so the nesting needs a limit to keep deeply nested input from
+// overflowing the stack, which is small in WebAssembly. Still far deeper than any collage that's
+// worth looking at.
+const MAX_DEPTH: usize = 1000;
+
+enum Tree {
+    Split(&'static str, Box<Tree>, Box<Tree>),
+    Image(usize),
+}
+
+pub fn parse(expression: &str) -> Result<Vec<BlueprintNode>, BlueprintError> {
+    let mut parser = Parser {
+        input: expression.as_bytes(),
+        position: 0,
+        depth: 0,
+    };
+    let tree = parser.tree()?;
+
+    parser.skip_whitespace();
+    if parser.position < parser.input.len() {
+        return Err(parser.error("expected the end of the expression"));
+    }
+
+    if let Tree::Image(_) = tree {
+        return Err(BlueprintError::InvalidExpression {
+            position: 0,
+            reason: "expected a split at the root, a collage needs at least two images".to_string(),
+        });
+    }
+
+    Ok(to_nodes(&tree))
+}
+
+// Expects a valid blueprint, see `LayoutBlueprint::to_expression`.
+pub fn print(nodes: &[BlueprintNode]) -> String {
+    let mut expression = String::new();
+
+    print_node(nodes, 0, &mut expression);
+
+    expression
+}
+
+fn print_node(nodes: &[BlueprintNode], index: usize, expression: &mut String) {
+    match &nodes[index] {
+        BlueprintNode::Internal { split, children } => {
+            expression.push_str(split);
+            expression.push('(');
+            print_node(nodes, children[0], expression);
+            expression.push(',');
+            print_node(nodes, children[1], expression);
+            expression.push(')');
+        }
+        BlueprintNode::Leaf { image, .. } => expression.push_str(&image.to_string()),
+    }
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    position: usize,
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn tree(&mut self) -> Result<Tree, BlueprintError> {
+        self.skip_whitespace();
+
+        let split = match self.peek() {
+            Some(b'V') => "V",
+            Some(b'H') => "H",
+            Some(byte) if byte.is_ascii_digit() => return self.image(),
+            _ => return Err(self.error("expected V, H or an image index")),
+        };
+        if self.depth == MAX_DEPTH {
+            return Err(self.error(&format!(
+                "splits can be nested at most {} levels deep",
+                MAX_DEPTH
+            )));
+        }
+        self.position += 1;
+
+        self.depth += 1;
+        self.expect(b'(')?;
+        let left = self.tree()?;
+        self.expect(b',')?;
+        let right = self.tree()?;
+        self.expect(b')')?;
+        self.depth -= 1;
+
+        Ok(Tree::Split(split, Box::new(left), Box::new(right)))
+    }
+
+    fn image(&mut self) -> Result<Tree, BlueprintError> {
+        let start = self.position;
+
+        while matches!(self.peek(), Some(byte) if byte.is_ascii_digit()) {
+            self.position += 1;
+        }
+
+        // Only ASCII digits, so the only thing that can go wrong is an overflow.
+        std::str::from_utf8(&self.input[start..self.position])
+            .unwrap()
+            .parse()
+            .map(Tree::Image)
+            .map_err(|_| BlueprintError::InvalidExpression {
+                position: start,
+                reason: "the image index is too large".to_string(),
+            })
+    }
+
+    fn expect(&mut self, expected: u8) -> Result<(), BlueprintError> {
+        self.skip_whitespace();
+
+        if self.peek() == Some(expected) {
+            self.position += 1;
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected {:?}", expected as char)))
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(byte) if byte.is_ascii_whitespace()) {
+            self.position += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.position).copied()
+    }
+
+    fn error(&self, reason: &str) -> BlueprintError {
+        BlueprintError::InvalidExpression {
+            position: self.position,
+            reason: reason.to_string(),
+        }
+    }
+}
+
+// Internal nodes in breadth-first order, then the leaves of each of them. Children are numbered
+// in the same order in which they're visited, so no lookups are needed.
+fn to_nodes(root: &Tree) -> Vec<BlueprintNode> {
+    let mut internal_trees = vec![];
+    let mut to_visit = VecDeque::from([root]);
+
+    while let Some(tree) = to_visit.pop_front() {
+        if let Tree::Split(_, left, right) = tree {
+            internal_trees.push(tree);
+            to_visit.push_back(left);
+            to_visit.push_back(right);
+        }
+    }
+
+    let mut next_internal = 1;
+    let mut next_leaf = internal_trees.len();
+    let mut leaves = vec![];
+    let mut number = |tree: &Tree| match tree {
+        Tree::Split(..) => {
+            next_internal += 1;
+            next_internal - 1
+        }
+        Tree::Image(image) => {
+            leaves.push(BlueprintNode::Leaf {
+                image: *image,
+                hash: None,
+            });
+            next_leaf += 1;
+            next_leaf - 1
+        }
+    };
+
+    let mut nodes: Vec<BlueprintNode> = internal_trees
+        .iter()
+        .map(|tree| match tree {
+            Tree::Split(split, left, right) => BlueprintNode::Internal {
+                split: split.to_string(),
+                children: vec![number(left), number(right)],
+            },
+            Tree::Image(_) => unreachable!(),
+        })
+        .collect();
+    nodes.append(&mut leaves);
+
+    nodes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blueprint::LayoutBlueprint;
+
+    #[test]
+    fn expressions_round_trip_through_blueprints() {
+        for expression in [
+            "V(0,1)",
+            "H(V(0,1),2)",
+            "V(H(0,1),V(2,H(3,4)))",
+            "H(V(3,H(0,4)),V(H(2,1),5))",
+        ] {
+            let blueprint = LayoutBlueprint::from_expression(expression, 10, 10).unwrap();
+
+            assert_eq!(expression, blueprint.to_expression().unwrap());
+        }
+    }
+
+    #[test]
+    fn nodes_are_ordered_like_in_layout_blueprints() {
+        let blueprint = LayoutBlueprint::from_expression("V(H(1,2),0)", 10, 10).unwrap();
+
+        assert_eq!(
+            LayoutBlueprint::from_legacy(10, 10, vec![("V".into(), vec![1]), ("H".into(), vec![])]),
+            blueprint
+        );
+    }
+
+    #[test]
+    fn whitespace_is_ignored() {
+        assert_eq!(
+            parse("V(H(0,1),2)").unwrap(),
+            parse(" V ( H(0, 1),\n  2 ) ").unwrap()
+        );
+    }
+
+    #[test]
+    fn errors_point_to_the_problem() {
+        let error = |expression| match parse(expression) {
+            Err(BlueprintError::InvalidExpression { position, .. }) => position,
+            result => panic!("{:?} parsed as {:?}", expression, result),
+        };
+
+        assert_eq!(0, error(""));
+        assert_eq!(0, error("X(0,1)"));
+        assert_eq!(4, error("V(0 1)"));
+        assert_eq!(5, error("V(0,1"));
+        assert_eq!(6, error("V(0,1))"));
+        assert_eq!(0, error("0"));
+        assert_eq!(2, error("V(99999999999999999999999,1)"));
+    }
+
+    #[test]
+    fn long_expressions_are_parsed_in_linear_time() {
+        fn balanced(images: std::ops::Range<usize>, expression: &mut String) {
+            if images.len() == 1 {
+                expression.push_str(&images.start.to_string());
+                return;
+            }
+
+            let middle = images.start + images.len() / 2;
+            expression.push_str("V(");
+            balanced(images.start..middle, expression);
+            expression.push(',');
+            balanced(middle..images.end, expression);
+            expression.push(')');
+        }
+
+        let mut expression = String::new();
+        balanced(0..100_000, &mut expression);
+        let blueprint = LayoutBlueprint::from_expression(&expression, 10, 10).unwrap();
+
+        assert_eq!(199_999, blueprint.nodes.len());
+        assert_eq!(expression, blueprint.to_expression().unwrap());
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let nested = |depth: usize| {
+            let mut expression = "V(0,".repeat(depth - 1);
+            expression.push_str("V(0,1)");
+            expression.push_str(&")".repeat(depth - 1));
+            expression
+        };
+
+        assert!(parse(&nested(MAX_DEPTH)).is_ok());
+        assert!(matches!(
+            parse(&nested(MAX_DEPTH + 1)),
+            Err(BlueprintError::InvalidExpression { position, .. }) if position == MAX_DEPTH * 4
+        ));
+        assert!(matches!(
+            LayoutBlueprint::from_expression(&"V(".repeat(1_000_000), 10, 10),
+            Err(BlueprintError::InvalidExpression { .. })
+        ));
+    }
+}
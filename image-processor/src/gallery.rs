@@ -0,0 +1,664 @@
+// Layouts which aren't slicing trees, for when a predictable gallery is preferred over a collage:
+//
+//     justified-rows   ┌───┬─────┬──┐   rows of images with the same height, each of them as wide
+//                      ├──┬┴─┬───┴──┤   as the gallery, except for the last one, which keeps the
+//                      ├──┴┬─┴┬─────┘   row height rather than growing much taller
+//                      └───┴──┘
+//
+//     masonry          ┌──┬──┬──┐      columns of the same width, each image going to the one
+//                      │  ├──┤  │      which is the shortest so far, optionally trimmed to a
+//                      ├──┤  ├──┤      straight bottom edge
+//                      │  ├──┘  │
+//                      └──┘     └
+//
+// The images keep their order, and nothing is searched for, so the same images always end up in
+// the same place. They also keep their aspect ratio, except in trimmed masonry, where the images
+// of each column are cropped a little, see `Masonry::trim`. The result is a list of tiles, same as
+// for a slicing tree, so the renderer and the encoders don't need to know where it came from.
+// There's no blueprint for it though.
+
+use crate::error::CollageError;
+use crate::layout::Dimensions;
+use crate::options::LayoutOptions;
+use crate::renderer::{Crop, LayoutMetadata, Tile};
+use image::RgbaImage;
+use serde::Deserialize;
+
+// The widest gallery there can be, in pixels, which is also the most that JPEG supports. Without
+// a limit, the sizes coming from JS could overflow.
+pub const MAX_WIDTH: u32 = 65_535;
+// Far more than would fit on any screen, but keeps the columns from taking up memory for nothing.
+pub const MAX_COLUMNS: u32 = 100;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(tag = "mode", rename_all = "kebab-case")]
+pub enum Gallery {
+    JustifiedRows(JustifiedRows),
+    Masonry(Masonry),
+}
+
+// E.g. `{mode: "justified-rows", width: 1200, row_height: 240}`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct JustifiedRows {
+    // Of the whole gallery in pixels, margins included.
+    pub width: u32,
+    // What the height of each row should be close to. Rows get a bit shorter or taller to be
+    // exactly as wide as the gallery.
+    pub row_height: u32,
+}
+
+impl Default for JustifiedRows {
+    fn default() -> Self {
+        JustifiedRows {
+            width: 1200,
+            row_height: 240,
+        }
+    }
+}
+
+// E.g. `{mode: "masonry", columns: 4, column_width: 300, trim: true}`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct Masonry {
+    pub columns: u32,
+    // In pixels, without the gutters.
+    pub column_width: u32,
+    // Ends all columns at the same height, their average one. The images in each column get a bit
+    // shorter or taller to make up the difference, and are cropped in the middle to keep them
+    // from being squashed or stretched.
+    pub trim: bool,
+}
+
+impl Default for Masonry {
+    fn default() -> Self {
+        Masonry {
+            columns: 3,
+            column_width: 300,
+            trim: false,
+        }
+    }
+}
+
+// A tile before the size limits are applied, in fractional pixels.
+struct Placement {
+    image_index: usize,
+    x: f64,
+    y: f64,
+    width: f64,
+    height: f64,
+    // Whether the tile may have a different shape than the image.
+    cropped: bool,
+}
+
+impl Gallery {
+    pub fn validate(&self, options: &LayoutOptions) -> Result<(), CollageError> {
+        if options.aspect_ratio.is_some() {
+            return Err(CollageError::InvalidOptions(
+                "The height of a gallery depends on the images, so the aspect ratio can't be locked"
+                    .to_string(),
+            ));
+        }
+
+        match self {
+            Gallery::JustifiedRows(rows) => rows.validate(options),
+            Gallery::Masonry(masonry) => masonry.validate(options),
+        }
+    }
+
+    // Where each of the images goes. Gutters, margins and size limits are honoured, a locked
+    // aspect ratio isn't, see `validate`.
+    pub fn layout(
+        &self,
+        images: &[RgbaImage],
+        options: &LayoutOptions,
+    ) -> Result<LayoutMetadata, CollageError> {
+        self.validate(options)?;
+
+        if images.len() < 2 {
+            return Err(CollageError::TooFewImages(images.len()));
+        }
+
+        let (placements, dimensions) = match self {
+            Gallery::JustifiedRows(rows) => rows.place(images, options),
+            Gallery::Masonry(masonry) => masonry.place(images, options),
+        };
+
+        Ok(metadata(&placements, dimensions, images, options))
+    }
+}
+
+impl JustifiedRows {
+    pub fn validate(&self, options: &LayoutOptions) -> Result<(), CollageError> {
+        if self.row_height == 0 {
+            return Err(CollageError::InvalidOptions(
+                "Row height must be greater than zero".to_string(),
+            ));
+        }
+
+        if self.width as u64 <= options.margin as u64 * 2 {
+            return Err(CollageError::InvalidOptions(format!(
+                "Gallery width must be greater than the margins, got {}",
+                self.width
+            )));
+        }
+
+        if self.width > MAX_WIDTH {
+            return Err(CollageError::InvalidOptions(format!(
+                "Gallery width must be at most {}, got {}",
+                MAX_WIDTH, self.width
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn place(&self, images: &[RgbaImage], options: &LayoutOptions) -> (Vec<Placement>, Dimensions) {
+        let aspect_ratios: Vec<f64> = images
+            .iter()
+            .map(|image| image.width() as f64 / image.height() as f64)
+            .collect();
+        let margin = options.margin as f64;
+        let gutter = options.gutter as f64;
+        let inner_width = self.width as f64 - margin * 2.0;
+        let target = self.row_height as f64;
+
+        // The height at which the images fill the whole width, zero if the gutters alone take
+        // all of it.
+        let fill_height = |row: &[usize]| {
+            let width = inner_width - gutter * (row.len() - 1) as f64;
+            let aspect_ratio: f64 = row.iter().map(|image| aspect_ratios[*image]).sum();
+
+            (width / aspect_ratio).max(0.0)
+        };
+        // Twice as tall is as far off as half as tall.
+        let deviation = |height: f64| (height / target).max(target / height);
+
+        let mut rows: Vec<(Vec<usize>, f64)> = vec![];
+        let mut row: Vec<usize> = vec![];
+
+        for image in 0..images.len() {
+            row.push(image);
+
+            let height = fill_height(&row);
+
+            if height > target {
+                continue;
+            }
+
+            // The row has just become too short. Either it ends here, or it ends before this image
+            // and is a bit too tall instead, whichever is closer to the row height.
+            if row.len() > 1 {
+                let previous_height = fill_height(&row[..row.len() - 1]);
+
+                if deviation(previous_height) < deviation(height) {
+                    let image = row.pop().unwrap();
+                    rows.push((std::mem::take(&mut row), previous_height));
+                    row.push(image);
+
+                    let height = fill_height(&row);
+
+                    if height <= target {
+                        rows.push((std::mem::take(&mut row), height));
+                    }
+
+                    continue;
+                }
+            }
+
+            rows.push((std::mem::take(&mut row), height));
+        }
+
+        // Too few images to fill the last row, so it's left as tall as the rest, aligned left.
+        if !row.is_empty() {
+            rows.push((row, target));
+        }
+
+        let mut placements = Vec::with_capacity(images.len());
+        let mut y = margin;
+
+        for (row, height) in rows {
+            let mut x = margin;
+
+            for image in row {
+                let width = height * aspect_ratios[image];
+
+                placements.push(Placement {
+                    image_index: image,
+                    x,
+                    y,
+                    width,
+                    height,
+                    cropped: false,
+                });
+                x += width + gutter;
+            }
+
+            y += height + gutter;
+        }
+
+        let dimensions = Dimensions {
+            width: self.width,
+            height: ((y - gutter + margin).round() as u32).max(1),
+        };
+
+        (placements, dimensions)
+    }
+}
+
+impl Masonry {
+    pub fn validate(&self, options: &LayoutOptions) -> Result<(), CollageError> {
+        if self.columns == 0 || self.column_width == 0 {
+            return Err(CollageError::InvalidOptions(
+                "Masonry needs at least one column with a width greater than zero".to_string(),
+            ));
+        }
+
+        if self.columns > MAX_COLUMNS {
+            return Err(CollageError::InvalidOptions(format!(
+                "Masonry can have at most {} columns, got {}",
+                MAX_COLUMNS, self.columns
+            )));
+        }
+
+        match self.width(options) {
+            Some(width) if width <= MAX_WIDTH => Ok(()),
+            _ => Err(CollageError::InvalidOptions(format!(
+                "Masonry can be at most {} pixels wide, columns, gutters and margins included",
+                MAX_WIDTH
+            ))),
+        }
+    }
+
+    // Of the whole gallery, margins included. None if it doesn't even fit in a u32.
+    fn width(&self, options: &LayoutOptions) -> Option<u32> {
+        self.columns
+            .checked_mul(self.column_width)?
+            .checked_add((self.columns - 1).checked_mul(options.gutter)?)?
+            .checked_add(options.margin.checked_mul(2)?)
+    }
+
+    fn place(&self, images: &[RgbaImage], options: &LayoutOptions) -> (Vec<Placement>, Dimensions) {
+        let margin = options.margin as f64;
+        let gutter = options.gutter as f64;
+        let column_width = self.column_width as f64;
+
+        // The images of each column along with their heights, and the height of each column.
+        let mut columns: Vec<Vec<(usize, f64)>> = vec![vec![]; self.columns as usize];
+        let mut column_heights = vec![0.0; columns.len()];
+
+        for (index, image) in images.iter().enumerate() {
+            // The leftmost one if there's a tie.
+            let column = (0..columns.len())
+                .reduce(|shortest, column| {
+                    if column_heights[column] < column_heights[shortest] {
+                        column
+                    } else {
+                        shortest
+                    }
+                })
+                .unwrap();
+            let height = column_width * image.height() as f64 / image.width() as f64;
+
+            if !columns[column].is_empty() {
+                column_heights[column] += gutter;
+            }
+
+            column_heights[column] += height;
+            columns[column].push((index, height));
+        }
+
+        // Columns left without images, if there are more columns than images, don't count.
+        let filled_heights = column_heights
+            .iter()
+            .zip(&columns)
+            .filter(|(_, column)| !column.is_empty())
+            .map(|(height, _)| *height);
+        let bottom = if self.trim {
+            filled_heights.clone().sum::<f64>() / filled_heights.count() as f64
+        } else {
+            filled_heights.fold(0.0, f64::max)
+        };
+
+        let mut placements = Vec::with_capacity(images.len());
+
+        for (i, column) in columns.iter().enumerate() {
+            let x = margin + i as f64 * (column_width + gutter);
+            let gutters = gutter * column.len().saturating_sub(1) as f64;
+            // The gutters stay as they are, only the images are resized.
+            let stretch = if self.trim {
+                ((bottom - gutters) / (column_heights[i] - gutters)).max(0.0)
+            } else {
+                1.0
+            };
+            let mut y = margin;
+
+            for (image, height) in column {
+                placements.push(Placement {
+                    image_index: *image,
+                    x,
+                    y,
+                    width: column_width,
+                    height: height * stretch,
+                    cropped: self.trim,
+                });
+                y += height * stretch + gutter;
+            }
+        }
+
+        placements.sort_by_key(|placement| placement.image_index);
+
+        let dimensions = Dimensions {
+            width: self.width(options).expect("Masonry wider than validated"),
+            height: ((bottom + margin * 2.0).round() as u32).max(1),
+        };
+
+        (placements, dimensions)
+    }
+}
+
+// Turns the placements into tiles, scaled down to fit the size limits.
+fn metadata(
+    placements: &[Placement],
+    dimensions: Dimensions,
+    images: &[RgbaImage],
+    options: &LayoutOptions,
+) -> LayoutMetadata {
+    let scale = options.scale_to_fit(dimensions).min(1.0);
+    let Dimensions { width, height } = options.fit(dimensions);
+
+    // Same as in the renderer, the edges are rounded rather than the sizes, so that adjacent
+    // images still touch. Very narrow or flat images still get a tile of at least a pixel, rather
+    // than disappearing.
+    let scale_edges = |start: f64, size: f64, limit: u32| {
+        let scale_edge = |edge: f64| ((edge * scale).round() as u32).min(limit);
+        let start_edge = scale_edge(start).min(limit - 1);
+
+        (start_edge, scale_edge(start + size).max(start_edge + 1))
+    };
+
+    let tiles = placements
+        .iter()
+        .map(|placement| {
+            let (left, right) = scale_edges(placement.x, placement.width, width);
+            let (top, bottom) = scale_edges(placement.y, placement.height, height);
+            let image = &images[placement.image_index];
+            let tile = Dimensions {
+                width: right - left,
+                height: bottom - top,
+            };
+            let crop = Some(Crop::centered(
+                Dimensions::from_tuple(image.dimensions()),
+                tile,
+            ))
+            .filter(|crop| placement.cropped && (crop.width, crop.height) != image.dimensions());
+
+            Tile {
+                image_index: placement.image_index,
+                x: left,
+                y: top,
+                width: tile.width,
+                height: tile.height,
+                scale: tile.width as f64 / crop.map_or(image.width(), |crop| crop.width) as f64,
+                crop,
+            }
+        })
+        .collect();
+
+    LayoutMetadata {
+        width,
+        height,
+        tiles,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::images;
+
+    fn justified_rows(width: u32, row_height: u32) -> Gallery {
+        Gallery::JustifiedRows(JustifiedRows { width, row_height })
+    }
+
+    fn masonry(columns: u32, column_width: u32, trim: bool) -> Gallery {
+        Gallery::Masonry(Masonry {
+            columns,
+            column_width,
+            trim,
+        })
+    }
+
+    fn positions(metadata: &LayoutMetadata) -> Vec<(u32, u32, u32, u32)> {
+        metadata
+            .tiles
+            .iter()
+            .map(|tile| (tile.x, tile.y, tile.width, tile.height))
+            .collect()
+    }
+
+    #[test]
+    fn rows_fill_the_width() {
+        let images = images(&[
+            (200, 100),
+            (100, 100),
+            (100, 100),
+            (300, 100),
+            (100, 200),
+            (200, 100),
+        ]);
+        let metadata = justified_rows(400, 100)
+            .layout(&images, &LayoutOptions::default())
+            .unwrap();
+
+        assert_eq!((400, 314), (metadata.width, metadata.height));
+        assert_eq!(
+            vec![
+                // 4:1, exactly the row height.
+                (0, 0, 200, 100),
+                (200, 0, 100, 100),
+                (300, 0, 100, 100),
+                // 3.5:1, a bit too tall, but with the next image it'd be even more too short.
+                (0, 100, 343, 114),
+                (343, 100, 57, 114),
+                (0, 214, 200, 100),
+            ],
+            positions(&metadata)
+        );
+    }
+
+    #[test]
+    fn last_row_keeps_the_row_height() {
+        let images = images(&[(100, 100); 5]);
+        let metadata = justified_rows(300, 100)
+            .layout(&images, &LayoutOptions::default())
+            .unwrap();
+
+        assert_eq!((300, 200), (metadata.width, metadata.height));
+        assert_eq!((100, 100, 100, 100), positions(&metadata)[4]);
+    }
+
+    #[test]
+    fn gutters_and_margins_are_left_empty() {
+        let images = images(&[(100, 100); 4]);
+        let options = LayoutOptions {
+            gutter: 10,
+            margin: 20,
+            ..LayoutOptions::default()
+        };
+        let metadata = justified_rows(250, 100).layout(&images, &options).unwrap();
+
+        assert_eq!((250, 250), (metadata.width, metadata.height));
+        assert_eq!(
+            vec![
+                (20, 20, 100, 100),
+                (130, 20, 100, 100),
+                (20, 130, 100, 100),
+                (130, 130, 100, 100),
+            ],
+            positions(&metadata)
+        );
+    }
+
+    #[test]
+    fn gallery_is_scaled_down_to_the_limits() {
+        let images = images(&[(100, 100); 4]);
+        let options = LayoutOptions {
+            max_width: Some(100),
+            ..LayoutOptions::default()
+        };
+        let metadata = justified_rows(200, 100).layout(&images, &options).unwrap();
+
+        assert_eq!((100, 100), (metadata.width, metadata.height));
+        assert_eq!((50, 50, 50, 50), positions(&metadata)[3]);
+        assert_eq!(0.5, metadata.tiles[3].scale);
+    }
+
+    #[test]
+    fn images_go_to_the_shortest_column() {
+        let images = images(&[(100, 200), (100, 100), (100, 100), (100, 50)]);
+        let metadata = masonry(2, 100, false)
+            .layout(&images, &LayoutOptions::default())
+            .unwrap();
+
+        assert_eq!((200, 250), (metadata.width, metadata.height));
+        assert_eq!(
+            vec![
+                (0, 0, 100, 200),
+                (100, 0, 100, 100),
+                (100, 100, 100, 100),
+                // Both columns are equally tall at this point.
+                (0, 200, 100, 50),
+            ],
+            positions(&metadata)
+        );
+        assert!(metadata.tiles.iter().all(|tile| tile.crop.is_none()));
+    }
+
+    #[test]
+    fn trimmed_columns_end_at_the_same_height() {
+        let images = images(&[(100, 200), (100, 100), (100, 100), (100, 50)]);
+        let metadata = masonry(2, 100, true)
+            .layout(&images, &LayoutOptions::default())
+            .unwrap();
+
+        assert_eq!((200, 225), (metadata.width, metadata.height));
+        assert_eq!(
+            vec![
+                (0, 0, 100, 180),
+                (100, 0, 100, 113),
+                (100, 113, 100, 112),
+                (0, 180, 100, 45),
+            ],
+            positions(&metadata)
+        );
+        // Shortened, so cropped at the top and bottom.
+        assert_eq!(
+            Some(Crop {
+                x: 0,
+                y: 10,
+                width: 100,
+                height: 180,
+            }),
+            metadata.tiles[0].crop
+        );
+        // Lengthened, so cropped at the sides.
+        assert_eq!(
+            Some(Crop {
+                x: 6,
+                y: 0,
+                width: 88,
+                height: 100,
+            }),
+            metadata.tiles[1].crop
+        );
+    }
+
+    #[test]
+    fn columns_without_images_are_left_empty() {
+        let images = images(&[(100, 100), (100, 50)]);
+        let options = LayoutOptions {
+            gutter: 10,
+            ..LayoutOptions::default()
+        };
+        let metadata = masonry(3, 100, true).layout(&images, &options).unwrap();
+
+        assert_eq!((320, 75), (metadata.width, metadata.height));
+        assert_eq!(
+            vec![(0, 0, 100, 75), (110, 0, 100, 75)],
+            positions(&metadata)
+        );
+    }
+
+    #[test]
+    fn extreme_aspect_ratios_still_get_a_tile() {
+        let images = images(&[(1, 2000), (100, 100), (2000, 1)]);
+        let metadata = justified_rows(300, 100)
+            .layout(&images, &LayoutOptions::default())
+            .unwrap();
+
+        for tile in &metadata.tiles {
+            assert!(tile.width >= 1 && tile.height >= 1, "{:?}", tile);
+            assert!(tile.scale > 0.0, "{:?}", tile);
+        }
+    }
+
+    #[test]
+    fn oversized_justified_rows_are_rejected() {
+        assert!(justified_rows(MAX_WIDTH + 1, 100)
+            .validate(&LayoutOptions::default())
+            .is_err());
+        assert!(justified_rows(400, 100)
+            .validate(&LayoutOptions {
+                margin: u32::MAX,
+                ..LayoutOptions::default()
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn oversized_masonry_is_rejected() {
+        let options = LayoutOptions {
+            gutter: 10,
+            ..LayoutOptions::default()
+        };
+        let images = images(&[(100, 100), (100, 50)]);
+
+        assert!(masonry(70_000, 70_000, false)
+            .layout(&images, &options)
+            .is_err());
+        assert!(masonry(MAX_COLUMNS + 1, 1, false)
+            .validate(&options)
+            .is_err());
+        assert!(masonry(2, MAX_WIDTH / 2, false).validate(&options).is_err());
+        assert!(masonry(2, MAX_WIDTH / 2 - 5, false)
+            .validate(&options)
+            .is_ok());
+    }
+
+    #[test]
+    fn locked_aspect_ratio_is_rejected() {
+        let options = LayoutOptions {
+            aspect_ratio: Some("1:1".parse().unwrap()),
+            ..LayoutOptions::default()
+        };
+
+        assert!(matches!(
+            justified_rows(400, 100).validate(&options),
+            Err(CollageError::InvalidOptions(_))
+        ));
+    }
+
+    #[test]
+    fn gallery_deserializes_from_json() {
+        let gallery: Gallery =
+            serde_json::from_str(r#"{"mode": "justified-rows", "row_height": 300}"#).unwrap();
+
+        assert_eq!(justified_rows(1200, 300), gallery);
+
+        let gallery: Gallery =
+            serde_json::from_str(r#"{"mode": "masonry", "columns": 4, "trim": true}"#).unwrap();
+
+        assert_eq!(masonry(4, 300, true), gallery);
+    }
+}
@@ -1,8 +1,10 @@
 pub trait ImageForProcessing {
     fn page_orientation(&self) -> PageOrientation;
+    fn is_opaque(&self) -> bool;
+    fn content_hash(&self) -> String;
 }
 
-impl ImageForProcessing for image::RgbImage {
+impl ImageForProcessing for image::RgbaImage {
     fn page_orientation(&self) -> PageOrientation {
         use std::cmp::Ordering::*;
         use PageOrientation::*;
@@ -16,6 +18,27 @@ impl ImageForProcessing for image::RgbImage {
             Equal => Square,
         }
     }
+
+    fn is_opaque(&self) -> bool {
+        self.pixels().all(|pixel| pixel[3] == u8::MAX)
+    }
+
+    // 64-bit FNV-1a of the dimensions and the pixels. It needs to stay the same across builds, as
+    // it's stored in blueprints, so the hashers from std are out of the question.
+    fn content_hash(&self) -> String {
+        const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+        const PRIME: u64 = 0x100000001b3;
+
+        let dimensions = [self.width().to_le_bytes(), self.height().to_le_bytes()].concat();
+        let hash = dimensions
+            .iter()
+            .chain(self.as_raw().iter())
+            .fold(OFFSET_BASIS, |hash, byte| {
+                (hash ^ *byte as u64).wrapping_mul(PRIME)
+            });
+
+        format!("{:016x}", hash)
+    }
 }
 
 pub enum PageOrientation {
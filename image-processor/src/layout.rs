@@ -1,4 +1,8 @@
-use image::RgbImage;
+use crate::blueprint::{BlueprintNode, LayoutBlueprint};
+use crate::cost::{CostFunction, WeightedCost};
+use crate::error::CollageError;
+use crate::options::LayoutOptions;
+use image::RgbaImage;
 use itertools::Itertools;
 use petgraph::{
     dot::{Config, Dot},
@@ -12,21 +16,21 @@ use rand::{
     seq::IteratorRandom,
     Rng,
 };
-use serde::{Deserialize, Serialize};
 use std::collections::{HashSet, VecDeque};
 use std::ptr;
-
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
-pub struct LayoutBlueprint {
-    graph_representation: Vec<(String, Vec<usize>)>,
-    width: u32,
-    height: u32,
-}
+use std::sync::OnceLock;
 
 #[derive(Debug, Clone)]
 pub struct Layout<'a> {
     graph: LayoutGraph<'a>,
     pub canvas_dimensions: Dimensions,
+    options: LayoutOptions,
+    // All images that the leaf nodes point to, in the order they were given in.
+    images: &'a [RgbaImage],
+    // Proportions of the root before cropping, which `crop_stretch` needs for every node that
+    // gets measured. Doesn't depend on the canvas, so only changes to the tree or the options
+    // reset it, see `tree_changed`.
+    unstretched_proportions: OnceLock<Proportions>,
 }
 
 pub type LayoutGraph<'a> = Graph<NodeLabel<'a>, ()>;
@@ -34,7 +38,7 @@ pub type LayoutGraph<'a> = Graph<NodeLabel<'a>, ()>;
 #[derive(PartialEq, Clone, Copy)]
 pub enum NodeLabel<'a> {
     Internal(SliceDirection),
-    Leaf(&'a RgbImage),
+    Leaf(&'a RgbaImage),
 }
 use NodeLabel::*;
 
@@ -71,14 +75,14 @@ impl std::fmt::Debug for NodeLabel<'_> {
 }
 
 impl<'a> Layout<'a> {
-    pub fn new<R>(images: &'a [RgbImage], rng: &mut R) -> Self
+    pub fn new<R>(images: &'a [RgbaImage], rng: &mut R) -> Result<Self, CollageError>
     where
         R: Rng + Sized,
     {
         if images.len() < 2 {
             // The internal graph representation is a full binary tree, so it can't have less than
             // two images.
-            panic!("Attempted to create a layout with less than two images");
+            return Err(CollageError::TooFewImages(images.len()));
         }
         // According to the property of full binary trees, a full binary tree with N leaf nodes
         // must have (N - 1) internal nodes, hence (N * 2 - 1) nodes total.
@@ -91,6 +95,9 @@ impl<'a> Layout<'a> {
         let mut layout = Layout {
             graph,
             canvas_dimensions,
+            options: LayoutOptions::default(),
+            images,
+            unstretched_proportions: OnceLock::new(),
         };
         let mut random_images = images.choose_multiple(rng, images.len());
 
@@ -118,186 +125,208 @@ impl<'a> Layout<'a> {
             }
         }
 
-        layout
+        Ok(layout)
     }
 
-    // The blueprint's graph representation in the form of Vec<(String, Vec<usize>)> shows how the
-    // internal nodes are laid out:
-    //
-    //    * The first element of the tuple indicates slice direction, "V" or "H".
-    //    * The second element is an array of indices to child nodes from the same main Vec.
-    //
-    // So, a graph representation of this form in JavaScript…
-    //
-    //     [
-    //       ["V",  [1, 2]],
-    //       ["H"], [ ]],
-    //       ["V"], [ ]],
-    //     ]
-    //
-    // …represents a graph which looks like this:
-    //
-    //          ┌───┐
-    //          │ V │
-    //          └───┘
-    //            │
-    //      ┌─────┴─────┐
-    //      ▼           ▼
-    //    ┌───┐       ┌───┐
-    //    │ H │       │ V │
-    //    └───┘       └───┘
-    //
-    // Then the images are sequentially added as leaf nodes to any internal node that has less than
-    // two children, starting from the first added node to the last added node.
+    // See the blueprint module for the format.
     pub fn from_blueprint(
         blueprint: &LayoutBlueprint,
-        images: &'a [RgbImage],
-    ) -> Result<Self, String> {
-        let graph = LayoutGraph::new();
+        images: &'a [RgbaImage],
+    ) -> Result<Self, CollageError> {
+        blueprint.validate(images.len())?;
+
+        let image_indices = blueprint.resolve_images(images)?;
         let canvas_dimensions = Dimensions {
             width: blueprint.width,
             height: blueprint.height,
         };
-        let mut graph_indices: Vec<NodeIndex> =
-            Vec::with_capacity(blueprint.graph_representation.len());
         let mut layout = Layout {
-            graph,
+            graph: LayoutGraph::with_capacity(blueprint.nodes.len(), blueprint.nodes.len() - 1),
             canvas_dimensions,
+            options: LayoutOptions::default(),
+            images,
+            unstretched_proportions: OnceLock::new(),
         };
 
-        // Add internal nodes from the blueprint.
-        for (label_code, _) in blueprint.graph_representation.iter() {
-            let label = match label_code.as_str() {
-                "V" => Internal(Vertical),
-                "H" => Internal(Horizontal),
-                _ => {
-                    return Err(format!(
-                        "Unknown label in graph representation: {:?}",
-                        label_code
-                    ));
-                }
+        for (node, image_index) in blueprint.nodes.iter().zip(image_indices) {
+            let label = match (node, image_index) {
+                (BlueprintNode::Internal { split, .. }, _) if split == "V" => Internal(Vertical),
+                (BlueprintNode::Internal { .. }, _) => Internal(Horizontal),
+                (BlueprintNode::Leaf { .. }, Some(image_index)) => Leaf(&images[image_index]),
+                (BlueprintNode::Leaf { .. }, None) => unreachable!("Leaf without an image"),
             };
 
-            graph_indices.push(layout.graph.add_node(label))
-        }
-
-        // Add edges between internal nodes based on the blueprint.
-        for (parent_i, (_, child_indices)) in blueprint.graph_representation.iter().enumerate() {
-            for child_i in child_indices {
-                layout
-                    .graph
-                    .update_edge(graph_indices[parent_i], graph_indices[*child_i], ());
-            }
+            layout.graph.add_node(label);
         }
 
-        // Add images as leafs to nodes with less than two children, starting from the first added
-        // node to the last one.
-        let indexes_of_nodes_with_less_than_two_children: Vec<NodeIndex> = layout
-            .indexes_of_nodes_with_less_than_two_children()
-            .collect();
-        let mut images_iter = images.iter();
-
-        for index in indexes_of_nodes_with_less_than_two_children {
-            while layout.node_has_less_than_two_children(index) {
-                let image = images_iter.next().ok_or("Ran out of images")?;
-                layout.add_node(index, NodeLabel::Leaf(image));
+        // The left child has to be added first, see `children`.
+        for (parent_i, node) in blueprint.nodes.iter().enumerate() {
+            if let BlueprintNode::Internal { children, .. } = node {
+                for child_i in children {
+                    layout
+                        .graph
+                        .add_edge(NodeIndex::new(parent_i), NodeIndex::new(*child_i), ());
+                }
             }
         }
 
         Ok(layout)
     }
 
-    pub fn to_blueprint(&self) -> LayoutBlueprint {
-        let mut blueprint_with_node_indices = vec![];
+    // Applies the constraints to the layout. The canvas is scaled down right away if it's too big,
+    // and it stays within the limits when mutated later on.
+    pub fn with_options(mut self, options: LayoutOptions) -> Self {
+        self.options = options;
+        self.tree_changed();
+        self.fit_canvas();
+        self
+    }
 
-        for node in self.logical_bfs_iter() {
-            if let Internal(_) = node.node_label() {
-                let children = node.children().unwrap();
+    pub fn options(&self) -> &LayoutOptions {
+        &self.options
+    }
 
-                blueprint_with_node_indices
-                    .push((node.index, vec![children.0.index, children.1.index]));
-            }
-        }
+    pub fn images(&self) -> &'a [RgbaImage] {
+        self.images
+    }
 
-        let mut blueprint = Vec::with_capacity(blueprint_with_node_indices.len());
+    fn fit_canvas(&mut self) {
+        self.canvas_dimensions = self
+            .options
+            .fit(self.options.lock_aspect_ratio(self.canvas_dimensions));
+    }
 
-        for (index, children_indices) in &blueprint_with_node_indices {
-            let label = match self.graph[*index] {
-                Internal(Vertical) => "V".to_string(),
-                Internal(Horizontal) => "H".to_string(),
-                _ => {
-                    unreachable!();
-                }
-            };
-            let children = children_indices
+    // Internal nodes come first in breadth-first order, followed by the leaves of each of them in
+    // the same order. That's also how legacy blueprints are converted, so layouts made from them
+    // give back the same blueprint.
+    pub fn to_blueprint(&self) -> LayoutBlueprint {
+        let internal_nodes: Vec<LayoutNode> = self
+            .logical_bfs_iter()
+            .filter(|node| matches!(node.node_label(), Internal(_)))
+            .collect();
+        let leaf_nodes: Vec<LayoutNode> = internal_nodes
+            .iter()
+            .flat_map(|node| {
+                let (left, right) = node.children().unwrap();
+                [left, right]
+            })
+            .filter(|node| matches!(node.node_label(), Leaf(_)))
+            .collect();
+        let position = |index: NodeIndex| {
+            internal_nodes
                 .iter()
-                .filter_map(|child_index| {
-                    if let Internal(_) = self.graph[*child_index] {
-                        Some(
-                            blueprint_with_node_indices
-                                .iter()
-                                .position(|(i, _)| i == child_index)
-                                .unwrap_or_else(|| {
-                                    panic!("{:?} not found in blueprint", child_index)
-                                }),
-                        )
-                    } else {
-                        None
-                    }
-                })
-                .collect();
+                .chain(leaf_nodes.iter())
+                .position(|node| node.index == index)
+                .unwrap_or_else(|| panic!("{:?} not found in blueprint", index))
+        };
 
-            blueprint.push((label, children));
-        }
+        let internal_blueprint_nodes = internal_nodes.iter().map(|node| {
+            let (left, right) = node.children().unwrap();
+            let split = match node.node_label() {
+                Internal(Vertical) => "V",
+                Internal(Horizontal) => "H",
+                Leaf(_) => unreachable!(),
+            };
 
-        LayoutBlueprint {
-            graph_representation: blueprint,
-            width: self.canvas_dimensions.width,
-            height: self.canvas_dimensions.height,
-        }
+            BlueprintNode::Internal {
+                split: split.to_string(),
+                children: vec![position(left.index), position(right.index)],
+            }
+        });
+        let leaf_blueprint_nodes = leaf_nodes.iter().map(|node| BlueprintNode::Leaf {
+            image: node
+                .image_index()
+                .expect("Leaf node with an image from outside of the layout"),
+            hash: None,
+        });
+
+        LayoutBlueprint::new(
+            self.canvas_dimensions.width,
+            self.canvas_dimensions.height,
+            internal_blueprint_nodes
+                .chain(leaf_blueprint_nodes)
+                .collect(),
+        )
+    }
+
+    // See the expression module.
+    pub fn to_expression(&self) -> String {
+        self.to_blueprint()
+            .to_expression()
+            .expect("Layout with an invalid blueprint")
     }
 
     pub fn aspect_ratio(&self) -> f64 {
         self.root_node().aspect_ratio()
     }
 
+    // Dimensions of the whole collage, margins included.
     pub fn dimensions(&self) -> (u32, u32) {
-        self.root_node().dimensions().to_tuple()
+        let Dimensions { width, height } = self.root_node().dimensions();
+        let margins = self.options.margin * 2;
+
+        (width + margins, height + margins)
     }
 
-    // Smaller value is better.
-    pub fn cost(&self) -> f64 {
-        let number_of_images = self.leaf_nodes().count() as f64;
+    // The part of the canvas that's left for the images after taking away the margins.
+    fn inner_canvas_dimensions(&self) -> Dimensions {
+        let margins = self.options.margin * 2;
 
-        number_of_images * self.scale_factor() + self.coverage_of_canvas_area()
+        Dimensions {
+            width: self.canvas_dimensions.width.saturating_sub(margins),
+            height: self.canvas_dimensions.height.saturating_sub(margins),
+        }
     }
 
-    // Previous implementation of the cost function, useful for comparing new results to old ones.
-    pub fn old_cost(&self) -> f64 {
-        let number_of_images = self.leaf_nodes().count() as f64;
+    // With a crop tolerance, all images are widened (above 1) or narrowed (below 1) by this factor
+    // so that the layout fills the canvas, and cropped to fit. Widening all leaves of a slicing
+    // tree by the same factor widens the whole tree by it, so one factor is enough to match any
+    // shape of the canvas within the tolerance.
+    pub fn crop_stretch(&self) -> f64 {
+        let tolerance = self.options.crop_tolerance;
+
+        if tolerance == 0.0 {
+            return 1.0;
+        }
+
+        let canvas = self.inner_canvas_dimensions();
+        let Proportions { ratio, extra_width } = *self
+            .unstretched_proportions
+            .get_or_init(|| self.root_node().proportions_with(1.0));
+        // Gutters in horizontal splits change along with the images, which is left out here. The
+        // layout then doesn't fill the canvas to the last pixel, but it still fits on it.
+        let stretch = (canvas.width as f64 - extra_width) / (ratio * canvas.height as f64);
 
-        self.scale_factor() + number_of_images * self.coverage_of_canvas_area()
+        if stretch > 0.0 && stretch.is_finite() {
+            stretch.clamp(1.0 / (1.0 + tolerance), 1.0 + tolerance)
+        } else {
+            1.0
+        }
     }
 
-    fn coverage_of_canvas_area(&self) -> f64 {
-        1.0 - self
-            .leaf_nodes()
-            .map(|leaf_node| {
-                leaf_node.dimensions().size() as f64 / self.canvas_dimensions.size() as f64
-            })
-            .sum::<f64>()
+    // The cost under the default cost function, see the cost module. Smaller value is better.
+    pub fn cost(&self) -> f64 {
+        WeightedCost::default().cost(self)
     }
 
-    fn scale_factor(&self) -> f64 {
-        self.leaf_nodes()
+    // Without any size limits, the ideal layout keeps images at their original size. If the
+    // collage has to fit in a smaller area, all images are expected to shrink by the same factor
+    // instead, so the size limit doesn't punish every layout equally.
+    //
+    // The returned value is a factor for the area, not for the sides.
+    pub fn reference_scale(&self) -> f64 {
+        let max_size = match self.options.max_size() {
+            Some(max_size) => max_size,
+            None => return 1.0,
+        };
+        let total_original_size = self
+            .leaf_nodes()
             .map(|leaf_node| {
-                let original_image_size =
-                    Dimensions::from_tuple(leaf_node.image().unwrap().dimensions()).size() as f64;
-                let scaled_image_size = leaf_node.dimensions().size() as f64;
-
-                (scaled_image_size - original_image_size).abs() / original_image_size
+                Dimensions::from_tuple(leaf_node.image().unwrap().dimensions()).size() as f64
             })
-            .sum::<f64>()
+            .sum::<f64>();
+
+        (max_size / total_original_size).min(1.0)
     }
 
     pub fn swap_random_node_pair<R>(&mut self, rng: &mut R)
@@ -349,6 +378,7 @@ impl<'a> Layout<'a> {
             .index_twice_mut(random_node_index, other_node_index);
         *a = other_node_label;
         *b = random_node_label;
+        self.tree_changed();
     }
 
     pub fn randomize_width<R>(&mut self, rng: &mut R)
@@ -359,6 +389,7 @@ impl<'a> Layout<'a> {
         let new_width =
             width + rng.gen_range(-width + 1..=(2 * self.canvas_dimensions.height as i64));
         self.canvas_dimensions.width = new_width as u32;
+        self.fit_canvas();
     }
 
     pub fn randomize_height<R>(&mut self, rng: &mut R)
@@ -369,6 +400,7 @@ impl<'a> Layout<'a> {
         let new_height =
             height + rng.gen_range(-height + 1..=(2 * self.canvas_dimensions.width as i64));
         self.canvas_dimensions.height = new_height as u32;
+        self.fit_canvas();
     }
 
     pub fn randomize_dimensions_by_equal_factor<R>(&mut self, rng: &mut R)
@@ -378,14 +410,17 @@ impl<'a> Layout<'a> {
         let factor = rng.gen_range(0.5..=1.5);
         self.canvas_dimensions.height = (self.canvas_dimensions.height as f64 * factor) as u32;
         self.canvas_dimensions.width = (self.canvas_dimensions.width as f64 * factor) as u32;
+        self.fit_canvas();
     }
 
-    fn calculate_random_canvas_dimensions<R>(images: &'a [RgbImage], rng: &mut R) -> Dimensions
+    fn calculate_random_canvas_dimensions<R>(images: &'a [RgbaImage], rng: &mut R) -> Dimensions
     where
         R: Rng + Sized,
     {
-        let len_for_width = rng.gen_range(1..=images.len());
-        let len_for_height = rng.gen_range(1..=images.len());
+        // usize is sampled differently on 32-bit targets such as wasm, so the same seed would give
+        // different canvases in the browser and natively.
+        let len_for_width = rng.gen_range(1..=images.len() as u32) as usize;
+        let len_for_height = rng.gen_range(1..=images.len() as u32) as usize;
         let width = images
             .choose_multiple(rng, len_for_width)
             .map(|i| i.width())
@@ -419,26 +454,32 @@ impl<'a> Layout<'a> {
         self.graph.edges(idx).count() < 2
     }
 
+    // Has to be called after every change to the graph or the options.
+    fn tree_changed(&mut self) {
+        self.unstretched_proportions = OnceLock::new();
+    }
+
     fn add_node(&mut self, parent_idx: NodeIndex, node_label: NodeLabel<'a>) -> NodeIndex {
+        self.tree_changed();
         let idx = self.graph.add_node(node_label);
         self.graph.update_edge(parent_idx, idx, ());
         idx
     }
 
-    fn root_node(&self) -> LayoutNode {
+    fn root_node(&self) -> LayoutNode<'_> {
         let index = self.graph.externals(Direction::Incoming).next().unwrap();
 
         LayoutNode::new(self, index)
     }
 
-    pub fn internal_nodes(&self) -> impl Iterator<Item = LayoutNode> + '_ + Clone {
+    pub fn internal_nodes(&self) -> impl Iterator<Item = LayoutNode<'_>> + '_ + Clone {
         self.graph
             .node_indices()
             .filter(|idx| self.graph.edges(*idx).count() == 2)
             .map(|idx| self.at_index(idx))
     }
 
-    pub fn leaf_nodes(&self) -> impl Iterator<Item = LayoutNode> + '_ + Clone {
+    pub fn leaf_nodes(&self) -> impl Iterator<Item = LayoutNode<'_>> + '_ + Clone {
         self.graph
             .externals(Direction::Outgoing)
             .map(move |index| LayoutNode::new(self, index))
@@ -462,7 +503,7 @@ impl<'a> Layout<'a> {
     //     (…) swapping two subtrees each consisting of one I node and two L nodes is equivalent to
     //     swapping the labels of the two I nodes. Therefore, for the crossover, we were only
     //     interested in subtrees with at least three L nodes.
-    fn subtrees(&self) -> impl Iterator<Item = Subtree> + '_ + Clone {
+    fn subtrees(&self) -> impl Iterator<Item = Subtree<'_>> + '_ + Clone {
         self.internal_nodes().filter_map(|node| {
             let mut bfs = Bfs::new(&self.graph, node.index);
             let mut leaf_node_count: usize = 0;
@@ -484,7 +525,7 @@ impl<'a> Layout<'a> {
     fn subtree_pairs(
         &self,
         other: &'a Self,
-    ) -> impl Iterator<Item = (Subtree, Subtree)> + '_ + Clone {
+    ) -> impl Iterator<Item = (Subtree<'_>, Subtree<'_>)> + '_ + Clone {
         let self_subtrees = self.subtrees();
         let other_subtrees = other.subtrees();
 
@@ -503,6 +544,8 @@ impl<'a> Layout<'a> {
     }
 
     fn swap_subtree(&mut self, other: &Self, self_index: NodeIndex, other_index: NodeIndex) {
+        self.tree_changed();
+
         // Collect indices and images from the old subtree. They will be needed for preserving the
         // order of leaf nodes and the old nodes will be deleted later.
         let mut old_subtree_images = vec![];
@@ -589,7 +632,7 @@ impl<'a> Layout<'a> {
         Dot::with_config(&self.graph, &[Config::EdgeNoLabel])
     }
 
-    fn children(&self, node: &LayoutNode) -> Option<(LayoutNode, LayoutNode)> {
+    fn children(&self, node: &LayoutNode) -> Option<(LayoutNode<'_>, LayoutNode<'_>)> {
         let mut iterator = self.graph.neighbors(node.index);
 
         // As petgraph's docs say:
@@ -609,7 +652,7 @@ impl<'a> Layout<'a> {
         }
     }
 
-    fn parent(&self, node: &LayoutNode) -> Option<LayoutNode> {
+    fn parent(&self, node: &LayoutNode) -> Option<LayoutNode<'_>> {
         self.parent_index(node.index)
             .map(|index| LayoutNode::new(self, index))
     }
@@ -623,6 +666,8 @@ impl<'a> Layout<'a> {
     }
 
     fn swap_order_of_children(&mut self, node_index: NodeIndex) {
+        self.tree_changed();
+
         let children = self.at_index(node_index).children().unwrap();
         let child_0 = children.0.index;
         let child_1 = children.1.index;
@@ -656,7 +701,7 @@ impl<'a> Layout<'a> {
         ancestors
     }
 
-    fn node_label(&self, node: &LayoutNode) -> &NodeLabel {
+    fn node_label(&self, node: &LayoutNode) -> &NodeLabel<'_> {
         self.graph.node_weight(node.index).unwrap()
     }
 
@@ -666,7 +711,7 @@ impl<'a> Layout<'a> {
 
     // Logical as in it uses the `children` method to traverse the graph. `children` is also what
     // the renderer uses to render the layout.
-    fn logical_bfs_iter(&self) -> LogicalBfs {
+    fn logical_bfs_iter(&self) -> LogicalBfs<'_> {
         // Edge case in tests.
         if self.graph.node_count() == 0 {
             LogicalBfs::empty(self)
@@ -675,7 +720,7 @@ impl<'a> Layout<'a> {
         }
     }
 
-    fn logical_subtree_bfs_iter(&self, index: NodeIndex) -> LogicalBfs {
+    fn logical_subtree_bfs_iter(&self, index: NodeIndex) -> LogicalBfs<'_> {
         LogicalBfs::new(self, index)
     }
 
@@ -744,6 +789,8 @@ impl<'a> Layout<'a> {
     }
 }
 
+// The fields are only inspected by the assertion macro in tests.
+#[cfg_attr(not(test), allow(dead_code))]
 #[derive(Debug)]
 enum LogicalEqError<'a> {
     DifferentCanvasDimensions,
@@ -811,6 +858,7 @@ impl<'a> LayoutNode<'a> {
         LayoutNode { layout, index }
     }
 
+    // Ignores the gutters and cropping, see `proportions` for that.
     pub fn aspect_ratio(&self) -> f64 {
         use NodeLabel::*;
         use SliceDirection::*;
@@ -832,21 +880,98 @@ impl<'a> LayoutNode<'a> {
         }
     }
 
+    // With gutters, the width of a node is no longer proportional to its height, as the gutters
+    // inside of it don't scale along with the images. Instead, the width is a linear function of
+    // the height:
+    //
+    //     width = ratio * height + extra_width
+    //
+    // Without gutters and cropping, `extra_width` is always zero and `ratio` is equal to the aspect
+    // ratio.
+    pub fn proportions(&self) -> Proportions {
+        self.proportions_with(self.layout.crop_stretch())
+    }
+
+    // The layout only needs to work out the crop stretch once for the whole tree.
+    fn proportions_with(&self, crop_stretch: f64) -> Proportions {
+        let gutter = self.layout.options.gutter as f64;
+
+        match self.node_label() {
+            Leaf(image) => Proportions {
+                ratio: image.width() as f64 / image.height() as f64 * crop_stretch,
+                extra_width: 0.0,
+            },
+            Internal(direction) => {
+                let children = self.children().unwrap();
+                let (a, b) = (
+                    children.0.proportions_with(crop_stretch),
+                    children.1.proportions_with(crop_stretch),
+                );
+
+                match direction {
+                    // Both children have the same height and the gutter sits between them.
+                    Vertical => Proportions {
+                        ratio: a.ratio + b.ratio,
+                        extra_width: a.extra_width + b.extra_width + gutter,
+                    },
+                    // Both children have the same width, so the height of the node is
+                    //
+                    //     (width - a.extra_width) / a.ratio
+                    //         + (width - b.extra_width) / b.ratio
+                    //         + gutter
+                    //
+                    // which solved for the width gives the values below.
+                    Horizontal => {
+                        let ratio = 1.0 / (1.0 / a.ratio + 1.0 / b.ratio);
+
+                        Proportions {
+                            ratio,
+                            extra_width: ratio
+                                * (a.extra_width / a.ratio + b.extra_width / b.ratio - gutter),
+                        }
+                    }
+                }
+            }
+        }
+    }
+
     pub fn dimensions(&self) -> Dimensions {
-        let aspect_ratio = self.aspect_ratio();
-        let parent_dimensions = self
+        self.dimensions_with(self.layout.crop_stretch())
+    }
+
+    fn dimensions_with(&self, crop_stretch: f64) -> Dimensions {
+        let Proportions { ratio, extra_width } = self.proportions_with(crop_stretch);
+        let available_dimensions = self
             .parent()
-            .map(|n| n.dimensions())
-            .unwrap_or(self.layout.canvas_dimensions);
+            .map(|n| n.dimensions_for_children(crop_stretch))
+            .unwrap_or_else(|| self.layout.inner_canvas_dimensions());
 
-        let width = parent_dimensions
+        let width = available_dimensions
             .width
-            .min((aspect_ratio * parent_dimensions.height as f64) as u32);
-        let height = (width as f64 / aspect_ratio) as u32;
+            .min((ratio * available_dimensions.height as f64 + extra_width) as u32);
+        let height = ((width as f64 - extra_width) / ratio) as u32;
 
         Dimensions { width, height }
     }
 
+    // The dimensions of the node minus the gutter between its children.
+    fn dimensions_for_children(&self, crop_stretch: f64) -> Dimensions {
+        let Dimensions { width, height } = self.dimensions_with(crop_stretch);
+        let gutter = self.layout.options.gutter;
+
+        match self.node_label() {
+            Internal(Vertical) => Dimensions {
+                width: width.saturating_sub(gutter),
+                height,
+            },
+            Internal(Horizontal) => Dimensions {
+                width,
+                height: height.saturating_sub(gutter),
+            },
+            Leaf(_) => Dimensions { width, height },
+        }
+    }
+
     pub fn height(&self) -> u32 {
         self.dimensions().height
     }
@@ -859,7 +984,7 @@ impl<'a> LayoutNode<'a> {
         self.layout.node_label(self)
     }
 
-    pub fn image(&self) -> Option<&'a RgbImage> {
+    pub fn image(&self) -> Option<&'a RgbaImage> {
         if let NodeLabel::Leaf(image) = self.node_label() {
             return Some(image);
         }
@@ -867,6 +992,17 @@ impl<'a> LayoutNode<'a> {
         None
     }
 
+    // Position of the image in the slice that the layout was created with. Leaf nodes only point
+    // to the images, so they're compared by address rather than by their pixels.
+    pub fn image_index(&self) -> Option<usize> {
+        let image = self.image()?;
+
+        self.layout
+            .images
+            .iter()
+            .position(|other_image| ptr::eq(image, other_image))
+    }
+
     pub fn children(&self) -> Option<(LayoutNode<'a>, LayoutNode<'a>)> {
         self.layout.children(self)
     }
@@ -908,6 +1044,12 @@ impl<'a> LayoutNode<'a> {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Proportions {
+    pub ratio: f64,
+    pub extra_width: f64,
+}
+
 #[derive(Debug)]
 pub enum ChildSide {
     Left,
@@ -987,9 +1129,9 @@ impl<'a> Iterator for LogicalBfs<'a> {
     }
 }
 
-// Auxiliary function for creating blueprints in tests.
+// Auxiliary function for creating blueprints in tests, using the legacy format for brevity.
 #[cfg(test)]
-fn create_blueprint_from_slice(
+pub(crate) fn create_blueprint_from_slice(
     dimensions: (u32, u32),
     graph_representation: &[(&str, &[usize])],
 ) -> LayoutBlueprint {
@@ -998,11 +1140,7 @@ fn create_blueprint_from_slice(
         .map(|(label, indices)| (label.to_string(), indices.to_vec()))
         .collect();
 
-    LayoutBlueprint {
-        width: dimensions.0,
-        height: dimensions.1,
-        graph_representation,
-    }
+    LayoutBlueprint::from_legacy(dimensions.0, dimensions.1, graph_representation)
 }
 
 #[cfg(test)]
@@ -1014,10 +1152,10 @@ mod logical_bfs_tests {
         let blueprint =
             create_blueprint_from_slice((10, 10), &[("V", &[1, 2]), ("H", &[]), ("V", &[])]);
         let images = vec![
-            RgbImage::new(1, 1),
-            RgbImage::new(1, 2),
-            RgbImage::new(1, 3),
-            RgbImage::new(1, 4),
+            RgbaImage::new(1, 1),
+            RgbaImage::new(1, 2),
+            RgbaImage::new(1, 3),
+            RgbaImage::new(1, 4),
         ];
         let layout = Layout::from_blueprint(&blueprint, &images).unwrap();
 
@@ -1043,10 +1181,10 @@ mod logical_bfs_tests {
         let blueprint =
             create_blueprint_from_slice((10, 10), &[("V", &[1, 2]), ("H", &[]), ("V", &[])]);
         let images = vec![
-            RgbImage::new(1, 1),
-            RgbImage::new(1, 2),
-            RgbImage::new(1, 3),
-            RgbImage::new(1, 4),
+            RgbaImage::new(1, 1),
+            RgbaImage::new(1, 2),
+            RgbaImage::new(1, 3),
+            RgbaImage::new(1, 4),
         ];
         let layout = Layout::from_blueprint(&blueprint, &images).unwrap();
 
@@ -1058,10 +1196,10 @@ mod logical_bfs_tests {
         let blueprint =
             create_blueprint_from_slice((10, 10), &[("V", &[1, 2]), ("H", &[]), ("V", &[])]);
         let images = vec![
-            RgbImage::new(1, 1),
-            RgbImage::new(1, 2),
-            RgbImage::new(1, 3),
-            RgbImage::new(1, 4),
+            RgbaImage::new(1, 1),
+            RgbaImage::new(1, 2),
+            RgbaImage::new(1, 3),
+            RgbaImage::new(1, 4),
         ];
         let layout = Layout::from_blueprint(&blueprint, &images).unwrap();
         let other_blueprint =
@@ -1075,6 +1213,7 @@ mod logical_bfs_tests {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::cost::CostTerm;
     use rand_core::SeedableRng;
     use rand_pcg::Pcg64;
 
@@ -1136,10 +1275,16 @@ mod tests {
         let layout_1 = Layout {
             graph: LayoutGraph::new(),
             canvas_dimensions: Dimensions::from_tuple((1, 1)),
+            options: LayoutOptions::default(),
+            images: &[],
+            unstretched_proportions: OnceLock::new(),
         };
         let layout_2 = Layout {
             graph: LayoutGraph::new(),
             canvas_dimensions: Dimensions::from_tuple((1, 1)),
+            options: LayoutOptions::default(),
+            images: &[],
+            unstretched_proportions: OnceLock::new(),
         };
 
         assert_logical_eq_of_layouts!(layout_1, &layout_2);
@@ -1150,10 +1295,16 @@ mod tests {
         let layout_1 = Layout {
             graph: LayoutGraph::new(),
             canvas_dimensions: Dimensions::from_tuple((1, 1)),
+            options: LayoutOptions::default(),
+            images: &[],
+            unstretched_proportions: OnceLock::new(),
         };
         let layout_2 = Layout {
             graph: LayoutGraph::new(),
             canvas_dimensions: Dimensions::from_tuple((3, 7)),
+            options: LayoutOptions::default(),
+            images: &[],
+            unstretched_proportions: OnceLock::new(),
         };
 
         assert_ne!(layout_1, layout_2);
@@ -1164,10 +1315,16 @@ mod tests {
         let mut layout_1 = Layout {
             graph: LayoutGraph::new(),
             canvas_dimensions: Dimensions::from_tuple((1, 1)),
+            options: LayoutOptions::default(),
+            images: &[],
+            unstretched_proportions: OnceLock::new(),
         };
         let mut layout_2 = Layout {
             graph: LayoutGraph::new(),
             canvas_dimensions: Dimensions::from_tuple((1, 1)),
+            options: LayoutOptions::default(),
+            images: &[],
+            unstretched_proportions: OnceLock::new(),
         };
         layout_1.graph.add_node(Internal(Vertical));
         layout_2.graph.add_node(Internal(Vertical));
@@ -1180,10 +1337,16 @@ mod tests {
         let mut layout_1 = Layout {
             graph: LayoutGraph::new(),
             canvas_dimensions: Dimensions::from_tuple((1, 1)),
+            options: LayoutOptions::default(),
+            images: &[],
+            unstretched_proportions: OnceLock::new(),
         };
         let mut layout_2 = Layout {
             graph: LayoutGraph::new(),
             canvas_dimensions: Dimensions::from_tuple((1, 1)),
+            options: LayoutOptions::default(),
+            images: &[],
+            unstretched_proportions: OnceLock::new(),
         };
         layout_1.graph.add_node(Internal(Vertical));
         layout_2.graph.add_node(Internal(Horizontal));
@@ -1196,13 +1359,19 @@ mod tests {
         let mut layout_1 = Layout {
             graph: LayoutGraph::new(),
             canvas_dimensions: Dimensions::from_tuple((1, 1)),
+            options: LayoutOptions::default(),
+            images: &[],
+            unstretched_proportions: OnceLock::new(),
         };
         let mut layout_2 = Layout {
             graph: LayoutGraph::new(),
             canvas_dimensions: Dimensions::from_tuple((1, 1)),
+            options: LayoutOptions::default(),
+            images: &[],
+            unstretched_proportions: OnceLock::new(),
         };
-        let image_1 = RgbImage::new(1, 1);
-        let image_2 = RgbImage::new(2, 2);
+        let image_1 = RgbaImage::new(1, 1);
+        let image_2 = RgbaImage::new(2, 2);
 
         let root_index_1 = layout_1.graph.add_node(Internal(Vertical));
         layout_1.add_node(root_index_1, Leaf(&image_1));
@@ -1220,13 +1389,19 @@ mod tests {
         let mut layout_1 = Layout {
             graph: LayoutGraph::new(),
             canvas_dimensions: Dimensions::from_tuple((1, 1)),
+            options: LayoutOptions::default(),
+            images: &[],
+            unstretched_proportions: OnceLock::new(),
         };
         let mut layout_2 = Layout {
             graph: LayoutGraph::new(),
             canvas_dimensions: Dimensions::from_tuple((1, 1)),
+            options: LayoutOptions::default(),
+            images: &[],
+            unstretched_proportions: OnceLock::new(),
         };
-        let image_1 = RgbImage::new(1, 1);
-        let image_2 = RgbImage::new(2, 2);
+        let image_1 = RgbaImage::new(1, 1);
+        let image_2 = RgbaImage::new(2, 2);
 
         let root_index_1 = layout_1.graph.add_node(Internal(Vertical));
         layout_1.add_node(root_index_1, Leaf(&image_1));
@@ -1255,9 +1430,9 @@ mod tests {
         // }
         let blueprint = create_blueprint_from_slice((10, 10), &[("V", &[1]), ("H", &[])]);
         let images = vec![
-            RgbImage::new(5, 10),
-            RgbImage::new(2, 2),
-            RgbImage::new(2, 4),
+            RgbaImage::new(5, 10),
+            RgbaImage::new(2, 2),
+            RgbaImage::new(2, 4),
         ];
         let layout_from_blueprint = Layout::from_blueprint(&blueprint, &images);
 
@@ -1267,6 +1442,9 @@ mod tests {
         let mut expected_layout = Layout {
             graph,
             canvas_dimensions,
+            options: LayoutOptions::default(),
+            images: &[],
+            unstretched_proportions: OnceLock::new(),
         };
         let v_index = expected_layout.graph.add_node(Internal(Vertical));
         let h_index = expected_layout.graph.add_node(Internal(Horizontal));
@@ -1288,6 +1466,175 @@ mod tests {
         assert_logical_eq_of_layouts!(expected_layout, layout_from_blueprint.as_ref().unwrap());
     }
 
+    #[test]
+    fn creating_layout_with_less_than_two_images_fails() {
+        let images = vec![RgbaImage::new(1, 1)];
+
+        assert!(matches!(
+            Layout::new(&images, &mut rand::thread_rng()),
+            Err(CollageError::TooFewImages(1))
+        ));
+    }
+
+    #[test]
+    fn creating_layout_from_blueprint_with_too_few_images_fails() {
+        let blueprint = create_blueprint_from_slice((10, 10), &[("V", &[1]), ("H", &[])]);
+        let images = vec![RgbaImage::new(1, 1), RgbaImage::new(1, 2)];
+
+        assert!(matches!(
+            Layout::from_blueprint(&blueprint, &images),
+            Err(CollageError::InvalidBlueprint(_))
+        ));
+    }
+
+    #[test]
+    fn creating_layout_from_blueprint_with_unknown_label_fails() {
+        let blueprint = create_blueprint_from_slice((10, 10), &[("X", &[])]);
+        let images = vec![RgbaImage::new(1, 1), RgbaImage::new(1, 2)];
+
+        assert!(matches!(
+            Layout::from_blueprint(&blueprint, &images),
+            Err(CollageError::InvalidBlueprint(_))
+        ));
+    }
+
+    #[test]
+    fn size_limits_scale_the_canvas_down() {
+        let blueprint = create_blueprint_from_slice((400, 200), &[("V", &[])]);
+        let images = vec![RgbaImage::new(200, 200), RgbaImage::new(200, 200)];
+        let options = LayoutOptions {
+            max_width: Some(100),
+            ..LayoutOptions::default()
+        };
+
+        let layout = Layout::from_blueprint(&blueprint, &images)
+            .unwrap()
+            .with_options(options);
+
+        assert_eq!(Dimensions::from_tuple((100, 50)), layout.canvas_dimensions);
+        assert_eq!((100, 50), layout.dimensions());
+    }
+
+    #[test]
+    fn size_limits_lower_the_reference_size_in_the_cost() {
+        let blueprint = create_blueprint_from_slice((200, 100), &[("V", &[])]);
+        let images = vec![RgbaImage::new(200, 200), RgbaImage::new(200, 200)];
+        let options = LayoutOptions {
+            max_width: Some(200),
+            max_height: Some(100),
+            ..LayoutOptions::default()
+        };
+
+        let unlimited_layout = Layout::from_blueprint(&blueprint, &images).unwrap();
+        let limited_layout = unlimited_layout.clone().with_options(options);
+
+        // Both images are scaled down to 100x100, which is exactly what the limit requires.
+        assert!(CostTerm::ScaleDeviation.cost(&unlimited_layout) > 0.0);
+        assert_eq!(0.0, CostTerm::ScaleDeviation.cost(&limited_layout));
+    }
+
+    #[test]
+    fn locked_aspect_ratio_reshapes_the_canvas() {
+        let blueprint = create_blueprint_from_slice((400, 200), &[("V", &[])]);
+        let images = vec![RgbaImage::new(200, 200), RgbaImage::new(200, 200)];
+        let options = LayoutOptions {
+            aspect_ratio: Some("1:1".parse().unwrap()),
+            ..LayoutOptions::default()
+        };
+
+        let layout = Layout::from_blueprint(&blueprint, &images)
+            .unwrap()
+            .with_options(options);
+
+        assert_eq!(Dimensions::from_tuple((400, 400)), layout.canvas_dimensions);
+        // The images keep their own shape, the layout just no longer fills the whole canvas.
+        assert_eq!((400, 200), layout.dimensions());
+    }
+
+    #[test]
+    fn crop_tolerance_lets_the_layout_fill_the_canvas() {
+        let blueprint = create_blueprint_from_slice((300, 100), &[("V", &[])]);
+        let images = vec![RgbaImage::new(100, 100), RgbaImage::new(100, 100)];
+        let layout = |crop_tolerance| {
+            Layout::from_blueprint(&blueprint, &images)
+                .unwrap()
+                .with_options(LayoutOptions {
+                    crop_tolerance,
+                    ..LayoutOptions::default()
+                })
+        };
+
+        assert_eq!((200, 100), layout(0.0).dimensions());
+        // Each image can only get 25% wider.
+        assert_eq!((250, 100), layout(0.25).dimensions());
+        assert_eq!((300, 100), layout(0.5).dimensions());
+        assert_eq!(
+            vec![(150, 100), (150, 100)],
+            layout(0.5)
+                .leaf_nodes()
+                .map(|node| node.dimensions().to_tuple())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(0.0, CostTerm::CanvasCoverage.cost(&layout(0.5)));
+    }
+
+    #[test]
+    fn crop_stretch_follows_changes_to_the_tree() {
+        let blueprint = LayoutBlueprint::from_expression("V(H(0,1),V(2,3))", 300, 100).unwrap();
+        let images = vec![
+            RgbaImage::new(100, 100),
+            RgbaImage::new(100, 50),
+            RgbaImage::new(50, 100),
+            RgbaImage::new(100, 100),
+        ];
+        let options = LayoutOptions {
+            crop_tolerance: 0.5,
+            ..LayoutOptions::default()
+        };
+        let mut layout = Layout::from_blueprint(&blueprint, &images)
+            .unwrap()
+            .with_options(options);
+        let mut rng = Pcg64::seed_from_u64(1);
+
+        for _ in 0..20 {
+            let fresh_layout = Layout::from_blueprint(&layout.to_blueprint(), &images)
+                .unwrap()
+                .with_options(options);
+
+            assert_eq!(fresh_layout.crop_stretch(), layout.crop_stretch());
+
+            layout.swap_random_node_pair(&mut rng);
+        }
+    }
+
+    #[test]
+    fn gutters_and_margins_keep_the_aspect_ratio_of_images() {
+        let blueprint = create_blueprint_from_slice((235, 230), &[("V", &[1]), ("H", &[])]);
+        let images = vec![
+            RgbaImage::new(100, 200),
+            RgbaImage::new(100, 100),
+            RgbaImage::new(100, 100),
+        ];
+        let options = LayoutOptions {
+            gutter: 10,
+            margin: 10,
+            ..LayoutOptions::default()
+        };
+
+        let layout = Layout::from_blueprint(&blueprint, &images)
+            .unwrap()
+            .with_options(options);
+        let leaf_dimensions: Vec<(u32, u32)> = layout
+            .leaf_nodes()
+            .map(|node| node.dimensions().to_tuple())
+            .sorted()
+            .collect();
+
+        // 105 + 10 + 100 wide and 100 + 10 + 100 tall, plus the margins.
+        assert_eq!((235, 230), layout.dimensions());
+        assert_eq!(vec![(100, 100), (100, 100), (105, 210)], leaf_dimensions);
+    }
+
     // Since we only have two internal nodes, we know that if pass one of them to
     // `swap_with_random_node`, the other one will be the only other internal node. Thus we can
     // write a test case.
@@ -1295,9 +1642,9 @@ mod tests {
     fn swap_random_pair_of_internal_nodes() {
         let blueprint = create_blueprint_from_slice((10, 10), &[("V", &[1]), ("H", &[])]);
         let images = vec![
-            RgbImage::new(5, 10),
-            RgbImage::new(2, 2),
-            RgbImage::new(2, 4),
+            RgbaImage::new(5, 10),
+            RgbaImage::new(2, 2),
+            RgbaImage::new(2, 4),
         ];
         let mut layout = Layout::from_blueprint(&blueprint, &images).unwrap();
 
@@ -1319,7 +1666,7 @@ mod tests {
     #[test]
     fn swap_random_pair_of_leaf_nodes() {
         let blueprint = create_blueprint_from_slice((10, 10), &[("V", &[])]);
-        let images = vec![RgbImage::new(1, 1), RgbImage::new(2, 2)];
+        let images = vec![RgbaImage::new(1, 1), RgbaImage::new(2, 2)];
         let mut layout = Layout::from_blueprint(&blueprint, &images).unwrap();
 
         layout.swap_with_random_node(&mut rand::thread_rng(), NodeIndex::new(1));
@@ -1332,15 +1679,15 @@ mod tests {
     fn fall_back_to_swapping_leaf_nodes_if_all_internal_nodes_have_the_same_label() {
         let blueprint = create_blueprint_from_slice((10, 10), &[("V", &[1]), ("V", &[])]);
         let images = vec![
-            RgbImage::new(1, 1),
-            RgbImage::new(1, 2),
-            RgbImage::new(1, 3),
+            RgbaImage::new(1, 1),
+            RgbaImage::new(1, 2),
+            RgbaImage::new(1, 3),
         ];
         let mut layout = Layout::from_blueprint(&blueprint, &images).unwrap();
 
         layout.swap_with_random_node(&mut rand::thread_rng(), NodeIndex::new(0));
 
-        let actual_leaf_node_images: Vec<RgbImage> = layout
+        let actual_leaf_node_images: Vec<RgbaImage> = layout
             .leaf_nodes()
             .map(|node| node.image().unwrap())
             .cloned()
@@ -1352,13 +1699,13 @@ mod tests {
     #[test]
     fn fall_back_to_swapping_leaf_nodes_if_theres_one_internal_node() {
         let blueprint = create_blueprint_from_slice((10, 10), &[("V", &[])]);
-        let images = vec![RgbImage::new(1, 1), RgbImage::new(2, 2)];
+        let images = vec![RgbaImage::new(1, 1), RgbaImage::new(2, 2)];
         let mut layout = Layout::from_blueprint(&blueprint, &images).unwrap();
 
         layout.swap_with_random_node(&mut rand::thread_rng(), NodeIndex::new(0));
 
         let expected_leaf_node_images = vec![&images[1], &images[0]];
-        let actual_leaf_node_images: Vec<&RgbImage> = layout
+        let actual_leaf_node_images: Vec<&RgbaImage> = layout
             .leaf_nodes()
             .map(|node| node.image().unwrap())
             .collect();
@@ -1368,14 +1715,17 @@ mod tests {
 
     #[test]
     fn create_blueprint_from_layout() {
-        let images = vec![
-            RgbImage::new(1, 1),
-            RgbImage::new(1, 2),
-            RgbImage::new(1, 3),
+        let images = [
+            RgbaImage::new(1, 1),
+            RgbaImage::new(1, 2),
+            RgbaImage::new(1, 3),
         ];
         let mut layout = Layout {
             graph: LayoutGraph::new(),
             canvas_dimensions: Dimensions::from_tuple((10, 10)),
+            options: LayoutOptions::default(),
+            images: &images,
+            unstretched_proportions: OnceLock::new(),
         };
         let v_index = layout.graph.add_node(Internal(Vertical));
         let h_index = layout.graph.add_node(Internal(Horizontal));
@@ -1394,6 +1744,17 @@ mod tests {
         assert_eq!(expected_blueprint, actual_blueprint);
     }
 
+    #[test]
+    fn layouts_round_trip_through_expressions() {
+        let images = vec![RgbaImage::new(1, 1); 5];
+        let expression = "V(H(3,1),V(0,H(4,2)))";
+        let blueprint = LayoutBlueprint::from_expression(expression, 10, 10).unwrap();
+        let layout = Layout::from_blueprint(&blueprint, &images).unwrap();
+
+        assert_eq!(blueprint, layout.to_blueprint());
+        assert_eq!(expression, layout.to_expression());
+    }
+
     #[test]
     fn from_and_to_blueprint_returns_same_blueprint() {
         let seed = rand::thread_rng().gen();
@@ -1402,9 +1763,9 @@ mod tests {
         for _ in 0..100 {
             let mut images = vec![];
             for i in 0..rng.gen_range(2..10) {
-                images.push(RgbImage::new(1, i + 1));
+                images.push(RgbaImage::new(1, i + 1));
             }
-            let layout = Layout::new(&images, &mut rng);
+            let layout = Layout::new(&images, &mut rng).unwrap();
             let blueprint1 = layout.to_blueprint();
             let blueprint2 = Layout::from_blueprint(&blueprint1, &images)
                 .unwrap()
@@ -1425,10 +1786,10 @@ mod tests {
         let blueprint =
             create_blueprint_from_slice((10, 10), &[("V", &[1]), ("H", &[2]), ("H", &[])]);
         let images = vec![
-            RgbImage::new(1, 1),
-            RgbImage::new(1, 2),
-            RgbImage::new(1, 3),
-            RgbImage::new(1, 4),
+            RgbaImage::new(1, 1),
+            RgbaImage::new(1, 2),
+            RgbaImage::new(1, 3),
+            RgbaImage::new(1, 4),
         ];
         let layout = Layout::from_blueprint(&blueprint, &images).unwrap();
 
@@ -1441,11 +1802,11 @@ mod tests {
     #[test]
     fn find_pairs_in_subtrees() {
         let images = vec![
-            RgbImage::new(1, 1),
-            RgbImage::new(1, 2),
-            RgbImage::new(1, 3),
-            RgbImage::new(1, 4),
-            RgbImage::new(1, 5),
+            RgbaImage::new(1, 1),
+            RgbaImage::new(1, 2),
+            RgbaImage::new(1, 3),
+            RgbaImage::new(1, 4),
+            RgbaImage::new(1, 5),
         ];
         let blueprint1 = create_blueprint_from_slice(
             (10, 10),
@@ -1479,10 +1840,10 @@ mod tests {
         let blueprint =
             create_blueprint_from_slice((10, 10), &[("V", &[1, 2]), ("H", &[]), ("V", &[])]);
         let images = vec![
-            RgbImage::new(1, 1),
-            RgbImage::new(1, 2),
-            RgbImage::new(1, 3),
-            RgbImage::new(1, 4),
+            RgbaImage::new(1, 1),
+            RgbaImage::new(1, 2),
+            RgbaImage::new(1, 3),
+            RgbaImage::new(1, 4),
         ];
         let mut layout = Layout::from_blueprint(&blueprint, &images).unwrap();
 
@@ -1498,10 +1859,10 @@ mod tests {
         let blueprint =
             create_blueprint_from_slice((10, 10), &[("V", &[1, 2]), ("H", &[]), ("V", &[])]);
         let images = vec![
-            RgbImage::new(1, 1),
-            RgbImage::new(1, 2),
-            RgbImage::new(1, 3),
-            RgbImage::new(1, 4),
+            RgbaImage::new(1, 1),
+            RgbaImage::new(1, 2),
+            RgbaImage::new(1, 3),
+            RgbaImage::new(1, 4),
         ];
         let mut layout = Layout::from_blueprint(&blueprint, &images).unwrap();
 
@@ -1520,11 +1881,11 @@ mod tests {
     #[test]
     fn swapping_single_subtree() {
         let images1 = vec![
-            RgbImage::new(1, 1),
-            RgbImage::new(1, 2),
-            RgbImage::new(1, 3),
-            RgbImage::new(1, 4),
-            RgbImage::new(1, 5),
+            RgbaImage::new(1, 1),
+            RgbaImage::new(1, 2),
+            RgbaImage::new(1, 3),
+            RgbaImage::new(1, 4),
+            RgbaImage::new(1, 5),
         ];
         let blueprint1 = create_blueprint_from_slice(
             (10, 10),
@@ -1561,11 +1922,11 @@ mod tests {
     #[test]
     fn swapping_two_subtrees_keeps_logical_structure() {
         let images1 = vec![
-            RgbImage::new(1, 1),
-            RgbImage::new(1, 2),
-            RgbImage::new(1, 3),
-            RgbImage::new(1, 4),
-            RgbImage::new(1, 5),
+            RgbaImage::new(1, 1),
+            RgbaImage::new(1, 2),
+            RgbaImage::new(1, 3),
+            RgbaImage::new(1, 4),
+            RgbaImage::new(1, 5),
         ];
         let blueprint1 = create_blueprint_from_slice(
             (10, 10),
@@ -1603,11 +1964,11 @@ mod tests {
     #[test]
     fn swapping_the_whole_layout_keeps_logical_structure() {
         let images = vec![
-            RgbImage::new(1, 1),
-            RgbImage::new(1, 2),
-            RgbImage::new(1, 3),
-            RgbImage::new(1, 4),
-            RgbImage::new(1, 5),
+            RgbaImage::new(1, 1),
+            RgbaImage::new(1, 2),
+            RgbaImage::new(1, 3),
+            RgbaImage::new(1, 4),
+            RgbaImage::new(1, 5),
         ];
         let blueprint1 = create_blueprint_from_slice(
             (10, 10),
@@ -1,20 +1,50 @@
 #![feature(try_blocks)]
-#![feature(total_cmp)]
 
 mod algorithm;
+pub mod blueprint;
+#[cfg(all(feature = "console", target_arch = "wasm32"))]
+mod console;
+pub mod cost;
+mod error;
+mod exact;
+mod expression;
+pub mod gallery;
 mod image_for_processing;
 pub mod layout;
+pub mod options;
 mod orientation;
+pub mod output;
+mod partition;
 pub mod renderer;
+pub mod strategy;
+mod styling;
+pub mod templates;
+#[cfg(test)]
+mod test_utils;
 mod utils;
 
+pub use crate::algorithm::{LayoutSearch, SearchProgress, StopReason};
+pub use crate::blueprint::{BlueprintNode, LayoutBlueprint};
+use crate::cost::CostFunction;
+pub use crate::cost::WeightedCost;
+pub use crate::error::{BlueprintError, CollageError};
+pub use crate::gallery::Gallery;
 use crate::image_for_processing::{ImageForProcessing, PageOrientation::*};
-pub use crate::layout::{Layout, LayoutBlueprint};
-use image::{GenericImage, RgbImage};
-use rand_core::SeedableRng;
-use rand_pcg::Pcg64;
+use crate::layout::Dimensions;
+pub use crate::layout::Layout;
+pub use crate::options::{CollageOptions, GeneticParams, LayoutOptions, RenderOptions};
+pub use crate::output::{encode, EncodedImage, OutputFormat, OutputOptions};
+use crate::renderer::Point;
+pub use crate::renderer::{LayoutMetadata, Tile};
+pub use crate::strategy::Strategy;
+use crate::strategy::{Exact, Genetic, LayoutStrategy, Partition};
+pub use crate::templates::Template;
+use crate::utils::Timer;
+use image::RgbaImage;
+use serde::de::DeserializeOwned;
+use std::ops::ControlFlow;
+use std::sync::Arc;
 use wasm_bindgen::prelude::*;
-use web_sys::console;
 
 // When the `wee_alloc` feature is enabled, use `wee_alloc` as the global
 // allocator.
@@ -25,215 +55,460 @@ static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 #[wasm_bindgen(start)]
 pub fn setup() {
     utils::set_panic_hook();
+    utils::set_logger();
 }
 
+// Errors are thrown as JS exceptions, so the worker can catch them and reject the promise
+// instead of crashing.
+//
+// Both entry points return `{bytes, mime_type, layout, blueprint, search}`, see LayoutMetadata,
+// the blueprint module and SearchProgress for the last three. `search` is null unless the genetic
+// algorithm was used, `blueprint` is null for galleries.
+// `options` can be left undefined, see CollageOptions for what it accepts.
+//
+// `on_progress` is optional. It gets called after each generation of the genetic algorithm with
+// the fields of SearchProgress, plus the blueprint of the best layout whenever it changes.
+// Returning `false` from it stops the search early and renders the best layout found so far.
 #[wasm_bindgen]
-pub fn generate_layout(image_arrays: Vec<js_sys::Uint8Array>, seed: Option<u32>) -> Vec<u8> {
-    let mut images: Vec<RgbImage> = image_arrays
-        .into_iter()
-        .enumerate()
-        .map(|(i, image_array)| {
-            console::group_collapsed_1(&format!("processing image {}", i + 1).into());
-            console::time();
-            let result = array_to_image(&image_array.to_vec());
-            console::time_end();
-            console::group_end();
-            result
-        })
-        .collect();
-
-    let target;
-
-    if images.len() > 2 {
-        console::time_with_label("generating random layout");
-        let layout = match seed {
-            Some(seed) => {
-                let seed = seed as u64;
-                let mut rng = Pcg64::seed_from_u64(seed);
-                algorithm::generate_layout(&images, &mut rng, Some(seed)).unwrap()
+pub fn generate_layout(
+    image_arrays: Vec<js_sys::Uint8Array>,
+    seed: Option<u32>,
+    options: &JsValue,
+    on_progress: Option<js_sys::Function>,
+) -> Result<JsValue, JsValue> {
+    let options: CollageOptions = options_from_js(options)?;
+    let images = arrays_to_images(image_arrays)?;
+    let mut callback_error = None;
+    let collage = generate_collage_with_progress(
+        images,
+        seed.map(|seed| seed as u64),
+        &options,
+        |progress, best| {
+            let on_progress = match &on_progress {
+                Some(on_progress) => on_progress,
+                None => return ControlFlow::Continue(()),
+            };
+
+            match report_progress(on_progress, progress, best) {
+                Ok(false) => ControlFlow::Break(()),
+                Ok(true) => ControlFlow::Continue(()),
+                Err(err) => {
+                    callback_error = Some(err);
+                    ControlFlow::Break(())
+                }
             }
-            None => algorithm::generate_layout(&images, &mut rand::thread_rng(), None).unwrap(),
-        };
-        console::time_end_with_label("generating random layout");
-
-        console::group_1(&"Layout debug".into());
-        console::group_collapsed_1(&"Dot".into());
-        console::log_1(&format!("{:?}", layout.dot()).into());
-        console::group_end();
-        console::log_1(
-            &format!(
-                "Canvas dimensions: {:?}",
-                layout.canvas_dimensions.to_tuple()
-            )
-            .into(),
-        );
-        console::log_1(&format!("Dimensions: {:?}", layout.dimensions()).into());
-        console::log_1(&format!("Cost: {}", layout.cost()).into());
-        console::log_1(&format!("Old cost: {}", layout.old_cost()).into());
-        console::group_end();
-
-        console::time_with_label("rendering layout");
-        target = renderer::render_layout(&layout);
-        console::time_end_with_label("rendering layout");
-    } else if let ([image1, image2], _) = images.split_at_mut(2) {
-        console::time_with_label("combining two images");
-
-        target = match (image1.page_orientation(), image2.page_orientation()) {
-            (Landscape, Landscape) => make_portrait(image1, image2),
-            _ => make_landscape(image1, image2),
-        };
+        },
+    )?;
 
-        console::time_end_with_label("combining two images");
-    } else {
-        panic!("Less than two images received")
+    if let Some(err) = callback_error {
+        return Err(err);
     }
 
-    console::time_with_label("encoding end result");
-    let mut jpg_buffer: Vec<u8> = vec![];
-    let mut jpg_encoder = image::jpeg::JpegEncoder::new(&mut jpg_buffer);
-    jpg_encoder.encode_image(&target).unwrap();
-    console::time_end_with_label("encoding end result");
+    collage_to_js(collage, &options.output)
+}
+
+fn report_progress(
+    on_progress: &js_sys::Function,
+    progress: &SearchProgress,
+    best: &Layout,
+) -> Result<bool, JsValue> {
+    let value = serde_wasm_bindgen::to_value(progress)?;
+
+    if progress.improved {
+        js_sys::Reflect::set(
+            &value,
+            &"blueprint".into(),
+            &serde_wasm_bindgen::to_value(&best.to_blueprint())?,
+        )?;
+    }
 
-    jpg_buffer
+    // Anything but an explicit `false` keeps the search going.
+    Ok(on_progress.call1(&JsValue::NULL, &value)? != JsValue::FALSE)
 }
 
+// Galleries don't have a blueprint, as they're laid out the same way every time anyway. So with a
+// gallery in `options`, `layout_blueprint` has to be null or undefined.
 #[wasm_bindgen]
 pub fn render_specific_layout(
     layout_blueprint: &JsValue,
     image_arrays: Vec<js_sys::Uint8Array>,
-) -> Vec<u8> {
-    let layout_blueprint: LayoutBlueprint = layout_blueprint.into_serde().unwrap();
-    let images: Vec<RgbImage> = image_arrays
-        .into_iter()
-        .enumerate()
-        .map(|(i, image_array)| {
-            console::group_collapsed_1(&format!("processing image {}", i + 1).into());
-            console::time();
-            let result = array_to_image(&image_array.to_vec());
-            console::time_end();
-            console::group_end();
-            result
-        })
-        .collect();
-    let layout = Layout::from_blueprint(&layout_blueprint, &images).unwrap();
-
-    console::group_1(&"Layout debug".into());
-    console::group_collapsed_1(&"Dot".into());
-    console::log_1(&format!("{:?}", layout.dot()).into());
-    console::group_end();
-    console::log_1(
-        &format!(
-            "Canvas dimensions: {:?}",
-            layout.canvas_dimensions.to_tuple()
-        )
-        .into(),
-    );
-    console::log_1(&format!("Dimensions: {:?}", layout.dimensions()).into());
-    console::log_1(&format!("Cost: {}", layout.cost()).into());
-    console::log_1(&format!("Old cost: {}", layout.old_cost()).into());
-    console::group_end();
+    options: &JsValue,
+) -> Result<JsValue, JsValue> {
+    let options: CollageOptions = options_from_js(options)?;
+
+    if let Some(gallery) = options.gallery {
+        if !(layout_blueprint.is_undefined() || layout_blueprint.is_null()) {
+            return Err(CollageError::InvalidOptions(
+                "A gallery can't be rendered from a blueprint".to_string(),
+            )
+            .into());
+        }
+
+        let images = arrays_to_images(image_arrays)?;
+        let collage = render_gallery(&images, &gallery, &options)?;
+
+        return collage_to_js(collage, &options.output);
+    }
+
+    let layout_blueprint: LayoutBlueprint =
+        serde_wasm_bindgen::from_value(layout_blueprint.clone())
+            .map_err(|err| BlueprintError::Malformed(err.to_string()))
+            .map_err(CollageError::from)?;
+    let images = arrays_to_images(image_arrays)?;
+    let collage = render_blueprint(&layout_blueprint, &images, &options)?;
 
-    console::time_with_label("rendering layout");
-    let target = renderer::render_layout(&layout);
-    console::time_end_with_label("rendering layout");
+    collage_to_js(collage, &options.output)
+}
 
-    console::time_with_label("encoding end result");
-    let mut jpg_buffer: Vec<u8> = vec![];
-    let mut jpg_encoder = image::jpeg::JpegEncoder::new(&mut jpg_buffer);
-    jpg_encoder.encode_image(&target).unwrap();
-    console::time_end_with_label("encoding end result");
+// The rendered collage along with the position of each image in it and a blueprint which can be
+// stored to render the same layout again later.
+#[derive(Debug, Clone)]
+pub struct Collage {
+    pub image: RgbaImage,
+    pub layout: LayoutMetadata,
+    // None for galleries, which aren't slicing trees.
+    pub blueprint: Option<LayoutBlueprint>,
+    // How the genetic algorithm went, including why it stopped. None if it wasn't used.
+    pub search: Option<SearchProgress>,
+}
 
-    jpg_buffer
+// Decodes an image and rotates it according to its EXIF orientation tag, if present. Broken EXIF
+// data is logged and otherwise ignored, see the orientation module.
+pub fn decode_image(raw_image: &[u8]) -> Result<RgbaImage, CollageError> {
+    orientation::fix_if_needed(raw_image)
 }
 
-fn make_landscape(image1: &mut RgbImage, image2: &mut RgbImage) -> RgbImage {
-    if image1.dimensions() != image2.dimensions() {
-        console::time_with_label("fit height");
-        fit_height(image1, image2);
-        console::time_end_with_label("fit height");
+// Combines the images into a single collage. Unless a template or a gallery was picked, two
+// images are simply stitched together, anything above that (or two images with a locked aspect
+// ratio, spacing, styled tiles or different importance) get a layout from one of the strategies,
+// by default the genetic algorithm, see the strategy module.
+//
+// Passing the same seed with the same images and options results in the same collage, natively
+// and in the browser alike. The only exception is `time_limit_ms`, which makes the result depend on
+// how fast the machine is.
+pub fn generate_collage(
+    images: Vec<RgbaImage>,
+    seed: Option<u64>,
+    options: &CollageOptions,
+) -> Result<Collage, CollageError> {
+    generate_collage_with_progress(images, seed, options, |_, _| ControlFlow::Continue(()))
+}
+
+// Same as generate_collage, except that `on_progress` gets called after each generation of the
+// genetic algorithm with the best layout so far. Breaking out of it stops the search and renders
+// that layout. It's not called at all if the collage doesn't need the genetic algorithm.
+pub fn generate_collage_with_progress<F>(
+    images: Vec<RgbaImage>,
+    seed: Option<u64>,
+    options: &CollageOptions,
+    on_progress: F,
+) -> Result<Collage, CollageError>
+where
+    F: FnMut(&SearchProgress, &Layout) -> ControlFlow<()>,
+{
+    options.validate()?;
+
+    if let Some(gallery) = options.gallery {
+        return render_gallery(&images, &gallery, options);
     }
 
-    let mut target = RgbImage::new(
-        image1.width() + image2.width(),
-        image1.height().max(image2.height()),
-    );
+    if let Some(template) = options.layout.template {
+        let layout = template.layout(&images)?.with_options(options.layout);
+
+        log_layout(&layout);
+
+        return render(&layout, options);
+    }
+
+    if images.len() > 2
+        || (images.len() == 2
+            && (options.layout.needs_layout()
+                || !options.render.tile.is_plain()
+                || !options.importance.is_empty()))
+    {
+        let cost_function = Arc::new(options.cost_function(images.len())?);
+
+        let strategy = options.strategy(images.len())?;
+        let timer = Timer::start(format!("generating layout ({:?})", strategy));
+        let mut layout_strategy: Box<dyn LayoutStrategy<'_>> = match strategy {
+            Strategy::Exact => Box::new(Exact),
+            Strategy::Partition => Box::new(Partition),
+            Strategy::Genetic | Strategy::Auto => Box::new(Genetic {
+                params: options.genetic,
+                seed,
+                on_progress,
+            }),
+        };
+        let (layout, search) =
+            layout_strategy.generate_layout(&images, &options.layout, cost_function)?;
+        timer.stop();
 
-    target.copy_from(image1, 0, 0).unwrap();
-    target.copy_from(image2, image1.width(), 0).unwrap();
+        log_layout(&layout);
 
-    target
+        Ok(Collage {
+            search,
+            ..render(&layout, options)?
+        })
+    } else if images.len() == 2 {
+        let timer = Timer::start("combining two images");
+        let (layout, blueprint) = stitch(&images, &options.layout);
+        let image = renderer::render_tiles(&images, &layout, &options.render);
+        timer.stop();
+
+        Ok(Collage {
+            image,
+            layout,
+            blueprint: Some(blueprint.with_image_hashes(&images)),
+            search: None,
+        })
+    } else {
+        Err(CollageError::TooFewImages(images.len()))
+    }
 }
 
-fn make_portrait(image1: &mut RgbImage, image2: &mut RgbImage) -> RgbImage {
-    if image1.dimensions() != image2.dimensions() {
-        console::time_with_label("fit width");
-        fit_width(image1, image2);
-        console::time_end_with_label("fit width");
+pub fn render_blueprint(
+    layout_blueprint: &LayoutBlueprint,
+    images: &[RgbaImage],
+    options: &CollageOptions,
+) -> Result<Collage, CollageError> {
+    options.validate()?;
+
+    if options.gallery.is_some() {
+        return Err(CollageError::InvalidOptions(
+            "A gallery can't be rendered from a blueprint".to_string(),
+        ));
     }
 
-    let mut target = RgbImage::new(
-        image1.width().max(image2.width()),
-        image1.height() + image2.height(),
-    );
+    let layout = Layout::from_blueprint(layout_blueprint, images)?.with_options(options.layout);
 
-    target.copy_from(image1, 0, 0).unwrap();
-    target.copy_from(image2, 0, image1.height()).unwrap();
+    log_layout(&layout);
 
-    target
+    render(&layout, options)
 }
 
-fn array_to_image(array: &[u8]) -> RgbImage {
-    orientation::fix_if_needed(array)
+// Lays the images out as the given gallery rather than the one in `options`, if any. The other
+// options apply as usual.
+pub fn render_gallery(
+    images: &[RgbaImage],
+    gallery: &Gallery,
+    options: &CollageOptions,
+) -> Result<Collage, CollageError> {
+    options.validate()?;
+
+    let focal_points = options.focal_points(images.len())?;
+
+    let timer = Timer::start("laying out gallery");
+    let mut layout = gallery.layout(images, &options.layout)?;
+    layout.focus(images, focal_points);
+    let image = renderer::render_tiles(images, &layout, &options.render);
+    timer.stop();
+
+    Ok(Collage {
+        image,
+        layout,
+        blueprint: None,
+        search: None,
+    })
 }
 
-fn fit_height(image1: &mut RgbImage, image2: &mut RgbImage) {
-    use std::cmp::Ordering::*;
-    let taller;
-    let shorter;
+fn render(layout: &Layout, options: &CollageOptions) -> Result<Collage, CollageError> {
+    let focal_points = options.focal_points(layout.images().len())?;
+
+    let timer = Timer::start("rendering layout");
+    let mut metadata = renderer::layout_metadata(layout);
+    metadata.focus(layout.images(), focal_points);
+    let image = renderer::render_tiles(layout.images(), &metadata, &options.render);
+    timer.stop();
+
+    Ok(Collage {
+        image,
+        layout: metadata,
+        blueprint: Some(layout.to_blueprint().with_image_hashes(layout.images())),
+        search: None,
+    })
+}
 
-    match image1.height().cmp(&image2.height()) {
-        Greater => {
-            taller = image1;
-            shorter = image2;
-        }
-        _ => {
-            taller = image2;
-            shorter = image1;
-        }
+fn arrays_to_images(image_arrays: Vec<js_sys::Uint8Array>) -> Result<Vec<RgbaImage>, CollageError> {
+    image_arrays
+        .into_iter()
+        .enumerate()
+        .map(|(i, image_array)| {
+            let timer = Timer::start(format!("processing image {}", i + 1));
+            let result = decode_image(&image_array.to_vec());
+            timer.stop();
+            result
+        })
+        .collect()
+}
+
+fn collage_to_js(collage: Collage, output_options: &OutputOptions) -> Result<JsValue, JsValue> {
+    let result: JsValue = encode(&collage.image, output_options)?.into();
+    let layout = serde_wasm_bindgen::to_value(&collage.layout)?;
+
+    js_sys::Reflect::set(&result, &"layout".into(), &layout)?;
+    js_sys::Reflect::set(
+        &result,
+        &"blueprint".into(),
+        &serde_wasm_bindgen::to_value(&collage.blueprint)?,
+    )?;
+    js_sys::Reflect::set(
+        &result,
+        &"search".into(),
+        &serde_wasm_bindgen::to_value(&collage.search)?,
+    )?;
+
+    Ok(result)
+}
+
+fn options_from_js<T: DeserializeOwned + Default>(options: &JsValue) -> Result<T, CollageError> {
+    if options.is_undefined() || options.is_null() {
+        return Ok(T::default());
     }
 
-    // Scale the taller image down so that it has the same height as the shorter one.
-    let new_width = taller.width() * shorter.height() / taller.height();
-    *taller = image::imageops::resize(
-        taller,
-        new_width,
-        shorter.height(),
-        image::imageops::FilterType::Lanczos3,
+    serde_wasm_bindgen::from_value(options.clone())
+        .map_err(|err| CollageError::InvalidOptions(err.to_string()))
+}
+
+fn log_layout(layout: &Layout) {
+    // Formatting the layout isn't free, so skip it altogether if nobody's listening.
+    if !log::log_enabled!(log::Level::Debug) {
+        return;
+    }
+
+    log::debug!("Dot: {:?}", layout.dot());
+    log::debug!(
+        "Canvas dimensions: {:?}",
+        layout.canvas_dimensions.to_tuple()
     );
+    log::debug!("Dimensions: {:?}", layout.dimensions());
+    log::debug!("Cost: {}", layout.cost());
+    log::debug!("Old cost: {}", cost::LegacyCost.cost(layout));
 }
 
-fn fit_width(image1: &mut RgbImage, image2: &mut RgbImage) {
-    use std::cmp::Ordering::*;
-    let wider;
-    let narrower;
+// Two images don't need the genetic algorithm. They're put side by side, or one above the other if
+// both are landscape, with the bigger one scaled down to match the other.
+fn stitch(images: &[RgbaImage], options: &LayoutOptions) -> (LayoutMetadata, LayoutBlueprint) {
+    let original_dimensions: Vec<Dimensions> = images
+        .iter()
+        .map(|image| Dimensions::from_tuple(image.dimensions()))
+        .collect();
+    let (first, second) = (original_dimensions[0], original_dimensions[1]);
+
+    let (first, second, second_position, canvas_dimensions, split) =
+        match (images[0].page_orientation(), images[1].page_orientation()) {
+            (Landscape, Landscape) => {
+                let (first, second) = fit_width(first, second);
+                let canvas_dimensions = Dimensions {
+                    width: first.width.max(second.width),
+                    height: first.height + second.height,
+                };
+
+                (
+                    first,
+                    second,
+                    Point {
+                        x: 0,
+                        y: first.height,
+                    },
+                    canvas_dimensions,
+                    "H",
+                )
+            }
+            _ => {
+                let (first, second) = fit_height(first, second);
+                let canvas_dimensions = Dimensions {
+                    width: first.width + second.width,
+                    height: first.height.max(second.height),
+                };
+
+                (
+                    first,
+                    second,
+                    Point {
+                        x: first.width,
+                        y: 0,
+                    },
+                    canvas_dimensions,
+                    "V",
+                )
+            }
+        };
 
-    match image1.width().cmp(&image2.width()) {
-        Greater => {
-            wider = image1;
-            narrower = image2;
-        }
-        _ => {
-            wider = image2;
-            narrower = image1;
-        }
-    }
+    // Same as in the renderer, the edges are scaled rather than the sizes.
+    let scale = options.scale_to_fit(canvas_dimensions).min(1.0);
+    let scale_edge = |edge: u32| (edge as f64 * scale).round() as u32;
+    let Dimensions { width, height } = options.fit(canvas_dimensions);
+    let positions = [(Point { x: 0, y: 0 }, first), (second_position, second)];
 
-    // Scale the wider image down so that it has the same width as the narrower one.
-    let new_height = wider.height() * narrower.width() / wider.width();
-    *wider = image::imageops::resize(
-        wider,
-        narrower.width(),
-        new_height,
-        image::imageops::FilterType::Lanczos3,
+    let tiles = positions
+        .iter()
+        .zip(original_dimensions)
+        .enumerate()
+        .map(
+            |(image_index, ((coords, dimensions), original_dimensions))| {
+                let left = scale_edge(coords.x).min(width);
+                let top = scale_edge(coords.y).min(height);
+                let right = scale_edge(coords.x + dimensions.width).min(width);
+                let bottom = scale_edge(coords.y + dimensions.height).min(height);
+
+                Tile {
+                    image_index,
+                    x: left,
+                    y: top,
+                    width: right - left,
+                    height: bottom - top,
+                    scale: (right - left) as f64 / original_dimensions.width as f64,
+                    crop: None,
+                }
+            },
+        )
+        .collect();
+
+    let metadata = LayoutMetadata {
+        width,
+        height,
+        tiles,
+    };
+    let blueprint = LayoutBlueprint::new(
+        canvas_dimensions.width,
+        canvas_dimensions.height,
+        vec![
+            BlueprintNode::Internal {
+                split: split.to_string(),
+                children: vec![1, 2],
+            },
+            BlueprintNode::Leaf {
+                image: 0,
+                hash: None,
+            },
+            BlueprintNode::Leaf {
+                image: 1,
+                hash: None,
+            },
+        ],
     );
+
+    (metadata, blueprint)
+}
+
+// Scales the taller image down so that it has the same height as the shorter one.
+fn fit_height(first: Dimensions, second: Dimensions) -> (Dimensions, Dimensions) {
+    let height = first.height.min(second.height);
+    let fit = |dimensions: Dimensions| Dimensions {
+        width: dimensions.width * height / dimensions.height,
+        height,
+    };
+
+    (fit(first), fit(second))
+}
+
+// Scales the wider image down so that it has the same width as the narrower one.
+fn fit_width(first: Dimensions, second: Dimensions) -> (Dimensions, Dimensions) {
+    let width = first.width.min(second.width);
+    let fit = |dimensions: Dimensions| Dimensions {
+        width,
+        height: dimensions.height * width / dimensions.width,
+    };
+
+    (fit(first), fit(second))
 }
@@ -1,35 +1,563 @@
-use image::RgbImage;
-use image_processor::{layout::Layout, renderer};
+use image::RgbaImage;
+use image_processor::gallery::{Gallery, JustifiedRows, Masonry};
+use image_processor::options::{FocalPoint, GeneticPreset, Shadow, Stroke};
+use image_processor::output::ChromaSubsampling;
+use image_processor::{
+    CollageOptions, GeneticParams, LayoutBlueprint, OutputFormat, Strategy, Template,
+};
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::process;
+
+const USAGE: &str = "\
+Usage: collage [OPTIONS] --output <FILE> <IMAGE> <IMAGE>...
+
+Options:
+  -o, --output <FILE>      Where to write the collage
+  -s, --seed <NUMBER>      Seed for the layout generator, the same seed and images give the same result
+  -f, --format <FORMAT>    Output format, jpeg, png or webp (default: based on the output file extension)
+  -q, --quality <1-100>    JPEG quality (default: 75)
+      --chroma <RATIO>     JPEG chroma subsampling, 4:4:4, 4:2:2 or 4:2:0 (default: 4:2:2)
+      --aspect-ratio <W:H> Lock the shape of the collage, e.g. 16:9, 1:1 or 4:5
+      --crop-tolerance <N> Let each tile be wider or narrower than its image by up to this much, e.g. 0.2 for 20%, and crop the image to fill it
+      --focal-points <LIST> Part of each image to keep in view when cropping, as X:Y between 0 and 1, e.g. 0.5:0.3,0.5:0.5
+      --background <COLOR> Colour behind the images, #rrggbb, #rrggbbaa or transparent (default: #000000)
+      --corner-radius <PX> Round the corners of each image
+      --stroke <PX:COLOR>  Draw a border inside each image, e.g. 4:#ffffff
+      --shadow             Draw a soft drop shadow under each image
+      --gutter <PX>        Space between the images (default: 0)
+      --margin <PX>        Space around the images (default: 0)
+      --max-width <PX>     Scale the collage down so that it's at most this wide
+      --max-height <PX>    Scale the collage down so that it's at most this tall
+      --max-megapixels <N> Scale the collage down so that it has at most this many megapixels
+      --effort <PRESET>    How long to search for a layout, fast, balanced or thorough (default: balanced)
+      --generations <N>    Override the number of generations of the layout search
+      --population <N>     Override the population size of the layout search, at least 6
+      --time-limit <MS>    Stop the layout search after this many milliseconds and use the best layout so far
+      --strategy <NAME>    How to lay out the images, auto, exact, genetic or partition (default: auto)
+      --exact-search <N>   Try every possible layout for collages of up to N images, at most 7 (default: 6, 0 turns it off)
+      --importance <LIST> Relative size of each image, e.g. 3,1,1 to make the first of three images three times as big as the others
+      --template <NAME>    Use a classic layout instead of generating one, grid, hero-strip, hero-column or mosaic
+      --justified-rows <PX> Lay the images out in rows of the same height filling this width, instead of a collage
+      --row-height <PX>    Height that the justified rows should be close to (default: 240)
+      --masonry <N>        Lay the images out in N columns of the same width, instead of a collage
+      --column-width <PX>  Width of each masonry column (default: 300)
+      --trim               Crop the masonry columns to end at the same height
+      --blueprint <FILE>   Render a previously saved blueprint instead of generating a new layout
+      --save-blueprint <FILE> Save the blueprint of the collage, to render it again later
+      --layout-json <FILE> Also write the position of each image in the collage as JSON
+  -v, --verbose            Print diagnostics to stderr
+  -h, --help               Print this message";
+
+// Exit codes, loosely following sysexits.h.
+const EXIT_FAILURE: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+
+#[derive(Debug)]
+struct Args {
+    inputs: Vec<PathBuf>,
+    output: PathBuf,
+    layout_json: Option<PathBuf>,
+    blueprint: Option<PathBuf>,
+    save_blueprint: Option<PathBuf>,
+    seed: Option<u64>,
+    options: CollageOptions,
+    verbose: bool,
+}
+
+enum ParseResult {
+    Run(Box<Args>),
+    Help,
+}
 
-// Test code for debugging layout creation. Output can be copied to the clipboard and used with
-// Graphviz.
 fn main() {
-    let mut images = vec![];
+    let code = run_with_exit_code(std::env::args().skip(1));
 
-    for i in 0..6 {
-        images.push(RgbImage::new(i * 100 + 100, 1000));
+    if code != 0 {
+        process::exit(code);
     }
+}
+
+fn run_with_exit_code(raw_args: impl Iterator<Item = String>) -> i32 {
+    let args = match parse_args(raw_args) {
+        Ok(ParseResult::Run(args)) => *args,
+        Ok(ParseResult::Help) => {
+            println!("{}", USAGE);
+            return 0;
+        }
+        Err(message) => {
+            eprintln!("error: {}\n\n{}", message, USAGE);
+            return EXIT_USAGE;
+        }
+    };
 
-    let layout = Layout::new(&images, &mut rand::thread_rng());
+    if args.verbose {
+        StderrLogger::init();
+    }
 
-    println!("{:?}", layout.dot());
-    println!("Canvas dimensions: {:?}", layout.canvas_dimensions);
-    println!("Dimensions: {:?}", layout.dimensions());
-    println!();
+    match run(args) {
+        Ok(()) => 0,
+        Err(message) => {
+            eprintln!("error: {}", message);
+            EXIT_FAILURE
+        }
+    }
+}
 
-    // let leaf_node = layout.leaf_nodes().last().unwrap();
+fn run(args: Args) -> Result<(), String> {
+    let images = args
+        .inputs
+        .iter()
+        .map(|path| {
+            let raw_image = std::fs::read(path)
+                .map_err(|err| format!("could not read {}: {}", path.display(), err))?;
 
-    // println!("leaf node: {:?}", leaf_node);
-    // println!();
+            image_processor::decode_image(&raw_image)
+                .map_err(|err| format!("{}: {}", path.display(), err))
+        })
+        .collect::<Result<Vec<RgbaImage>, String>>()?;
 
-    // for ancestor in leaf_node.ancestors() {
-    //     println!("parent: {:?}", ancestor);
-    //     println!("children: {:?}", ancestor.children());
-    //     println!();
-    // }
+    let collage = match &args.blueprint {
+        Some(path) => {
+            let json = std::fs::read(path)
+                .map_err(|err| format!("could not read {}: {}", path.display(), err))?;
+            let blueprint: LayoutBlueprint = serde_json::from_slice(&json)
+                .map_err(|err| format!("{}: {}", path.display(), err))?;
 
-    println!("Rendering layout");
-    println!();
+            image_processor::render_blueprint(&blueprint, &images, &args.options)
+        }
+        None => image_processor::generate_collage_with_progress(
+            images,
+            args.seed,
+            &args.options,
+            |progress, _| {
+                if progress.improved {
+                    log::debug!(
+                        "Generation {}/{}: cost {:.4} after {}ms",
+                        progress.generation,
+                        progress.generation_limit,
+                        progress.best_cost,
+                        progress.elapsed_ms
+                    );
+                }
 
-    renderer::render_layout(&layout);
+                ControlFlow::Continue(())
+            },
+        ),
+    }
+    .map_err(|err| err.to_string())?;
+    let encoded = image_processor::encode(&collage.image, &args.options.output)
+        .map_err(|err| err.to_string())?;
+
+    std::fs::write(&args.output, encoded.bytes)
+        .map_err(|err| format!("could not write {}: {}", args.output.display(), err))?;
+
+    if let Some(path) = &args.layout_json {
+        write_json(path, &collage.layout)?;
+    }
+
+    if let Some((path, blueprint)) = args.save_blueprint.zip(collage.blueprint) {
+        write_json(&path, &blueprint)?;
+    }
+
+    Ok(())
+}
+
+fn write_json(path: &Path, value: &impl serde::Serialize) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(value).map_err(|err| err.to_string())?;
+
+    std::fs::write(path, json).map_err(|err| format!("could not write {}: {}", path.display(), err))
+}
+
+fn format_from_path(path: &Path) -> Option<OutputFormat> {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .and_then(OutputFormat::from_name)
+}
+
+struct StderrLogger;
+
+impl StderrLogger {
+    fn init() {
+        static LOGGER: StderrLogger = StderrLogger;
+
+        log::set_logger(&LOGGER).expect("logger already set");
+        log::set_max_level(log::LevelFilter::Debug);
+    }
+}
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        eprintln!("[{}] {}", record.level(), record.args());
+    }
+
+    fn flush(&self) {}
+}
+
+fn parse_args(mut raw_args: impl Iterator<Item = String>) -> Result<ParseResult, String> {
+    let mut inputs = vec![];
+    let mut output: Option<PathBuf> = None;
+    let mut layout_json = None;
+    let mut blueprint = None;
+    let mut save_blueprint = None;
+    let mut seed = None;
+    let mut format = None;
+    let mut options = CollageOptions::default();
+    let mut verbose = false;
+    let mut preset = GeneticPreset::default();
+    let mut generation_limit = None;
+    let mut population_size = None;
+    let mut time_limit_ms = None;
+    let mut exact_search_limit = None;
+    let mut gallery_width = None;
+    let mut row_height = None;
+    let mut columns = None;
+    let mut column_width = None;
+    let mut trim = false;
+
+    while let Some(arg) = raw_args.next() {
+        let mut value_of = |name: &str| {
+            raw_args
+                .next()
+                .ok_or_else(|| format!("{} requires a value", name))
+        };
+
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(ParseResult::Help),
+            "-v" | "--verbose" => verbose = true,
+            "-o" | "--output" => output = Some(PathBuf::from(value_of(&arg)?)),
+            "--layout-json" => layout_json = Some(PathBuf::from(value_of(&arg)?)),
+            "--effort" => {
+                let value = value_of(&arg)?;
+                preset = GeneticPreset::from_name(&value)
+                    .ok_or_else(|| format!("unknown effort: {:?}", value))?;
+            }
+            "--generations" => generation_limit = Some(parse_positive(&value_of(&arg)?)?),
+            "--population" => population_size = Some(parse_positive(&value_of(&arg)?)?),
+            "--time-limit" => time_limit_ms = Some(parse_positive(&value_of(&arg)?)?),
+            "--strategy" => {
+                let value = value_of(&arg)?;
+                options.strategy = Strategy::from_name(&value)
+                    .ok_or_else(|| format!("unknown strategy: {:?}", value))?;
+            }
+            "--exact-search" => {
+                let value = value_of(&arg)?;
+                exact_search_limit = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("expected a number of images: {:?}", value))?,
+                );
+            }
+            "--importance" => options.importance = parse_importance(&value_of(&arg)?)?,
+            "--template" => {
+                let value = value_of(&arg)?;
+                options.layout.template = Some(
+                    Template::from_name(&value)
+                        .ok_or_else(|| format!("unknown template: {:?}", value))?,
+                );
+            }
+            "--justified-rows" => gallery_width = Some(parse_positive(&value_of(&arg)?)?),
+            "--row-height" => row_height = Some(parse_positive(&value_of(&arg)?)?),
+            "--masonry" => columns = Some(parse_positive(&value_of(&arg)?)?),
+            "--column-width" => column_width = Some(parse_positive(&value_of(&arg)?)?),
+            "--trim" => trim = true,
+            "--blueprint" => blueprint = Some(PathBuf::from(value_of(&arg)?)),
+            "--save-blueprint" => save_blueprint = Some(PathBuf::from(value_of(&arg)?)),
+            "-s" | "--seed" => {
+                let value = value_of(&arg)?;
+                seed = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("invalid seed: {:?}", value))?,
+                );
+            }
+            "-f" | "--format" => {
+                let value = value_of(&arg)?;
+                format = Some(
+                    OutputFormat::from_name(&value)
+                        .ok_or_else(|| format!("unsupported format: {:?}", value))?,
+                );
+            }
+            "-q" | "--quality" => {
+                let value = value_of(&arg)?;
+                options.output.quality = match value.parse() {
+                    Ok(quality @ 1..=100) => quality,
+                    _ => return Err(format!("quality must be between 1 and 100: {:?}", value)),
+                };
+            }
+            "--chroma" => {
+                let value = value_of(&arg)?;
+                options.output.chroma_subsampling = ChromaSubsampling::from_name(&value)
+                    .ok_or_else(|| format!("unsupported chroma subsampling: {:?}", value))?;
+            }
+            "--aspect-ratio" => {
+                let value = value_of(&arg)?;
+                options.layout.aspect_ratio = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("invalid aspect ratio, expected W:H: {:?}", value))?,
+                );
+            }
+            "--crop-tolerance" => {
+                let value = value_of(&arg)?;
+                options.layout.crop_tolerance = value
+                    .parse()
+                    .map_err(|_| format!("expected a number between 0 and 1: {:?}", value))?;
+            }
+            "--focal-points" => {
+                let value = value_of(&arg)?;
+                options.focal_points = value
+                    .split(',')
+                    .map(|point| point.parse::<FocalPoint>())
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| format!("invalid focal points, expected X:Y,...: {:?}", value))?;
+            }
+            "--background" => {
+                let value = value_of(&arg)?;
+                options.render.background = value
+                    .parse()
+                    .map_err(|_| format!("invalid colour: {:?}", value))?;
+            }
+            "--corner-radius" => {
+                options.render.tile.corner_radius = parse_pixels(&value_of(&arg)?)?
+            }
+            "--stroke" => options.render.tile.stroke = Some(parse_stroke(&value_of(&arg)?)?),
+            "--shadow" => options.render.tile.shadow = Some(Shadow::default()),
+            "--gutter" => options.layout.gutter = parse_pixels(&value_of(&arg)?)?,
+            "--margin" => options.layout.margin = parse_pixels(&value_of(&arg)?)?,
+            "--max-width" => options.layout.max_width = Some(parse_positive(&value_of(&arg)?)?),
+            "--max-height" => options.layout.max_height = Some(parse_positive(&value_of(&arg)?)?),
+            "--max-megapixels" => {
+                options.layout.max_megapixels = Some(parse_positive(&value_of(&arg)?)?)
+            }
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                return Err(format!("unknown option: {}", arg));
+            }
+            _ => inputs.push(PathBuf::from(arg)),
+        }
+    }
+
+    let output = output.ok_or("missing --output")?;
+
+    options.genetic = GeneticParams::preset(preset);
+    options.genetic.generation_limit = generation_limit.unwrap_or(options.genetic.generation_limit);
+    options.genetic.population_size = population_size.unwrap_or(options.genetic.population_size);
+    options.genetic.time_limit_ms = time_limit_ms;
+    options.genetic.exact_search_limit =
+        exact_search_limit.unwrap_or(options.genetic.exact_search_limit);
+    options.genetic.validate().map_err(|err| err.to_string())?;
+
+    options.gallery = match (gallery_width, columns) {
+        (Some(_), Some(_)) => {
+            return Err("--justified-rows and --masonry can't be combined".to_string());
+        }
+        (Some(width), None) => Some(Gallery::JustifiedRows(JustifiedRows {
+            width,
+            row_height: row_height.unwrap_or(JustifiedRows::default().row_height),
+        })),
+        (None, Some(columns)) => Some(Gallery::Masonry(Masonry {
+            columns,
+            column_width: column_width.unwrap_or(Masonry::default().column_width),
+            trim,
+        })),
+        (None, None) => None,
+    };
+
+    if row_height.is_some() && gallery_width.is_none() {
+        return Err("--row-height requires --justified-rows".to_string());
+    }
+
+    if (column_width.is_some() || trim) && columns.is_none() {
+        return Err("--column-width and --trim require --masonry".to_string());
+    }
+
+    if options.gallery.is_some() && (blueprint.is_some() || save_blueprint.is_some()) {
+        return Err("galleries aren't slicing trees, so they have no blueprint".to_string());
+    }
+
+    if inputs.len() < 2 {
+        return Err("at least two images are required".to_string());
+    }
+
+    options.output.format = match format.or_else(|| format_from_path(&output)) {
+        Some(format) => format,
+        None => {
+            return Err(format!(
+                "could not infer the format from {}, use --format",
+                output.display()
+            ))
+        }
+    };
+
+    Ok(ParseResult::Run(Box::new(Args {
+        inputs,
+        output,
+        layout_json,
+        blueprint,
+        save_blueprint,
+        seed,
+        options,
+        verbose,
+    })))
+}
+
+fn parse_positive<T>(value: &str) -> Result<T, String>
+where
+    T: std::str::FromStr + PartialOrd + Default,
+{
+    match value.parse() {
+        Ok(number) if number > T::default() => Ok(number),
+        _ => Err(format!("expected a positive number: {:?}", value)),
+    }
+}
+
+fn parse_importance(value: &str) -> Result<Vec<f64>, String> {
+    value
+        .split(',')
+        .map(|weight| parse_positive(weight.trim()))
+        .collect()
+}
+
+fn parse_pixels(value: &str) -> Result<u32, String> {
+    value
+        .parse()
+        .map_err(|_| format!("expected a number of pixels: {:?}", value))
+}
+
+fn parse_stroke(value: &str) -> Result<Stroke, String> {
+    let invalid = || format!("invalid stroke, expected PX:COLOR: {:?}", value);
+    let (width, color) = value.split_once(':').ok_or_else(invalid)?;
+
+    Ok(Stroke {
+        width: width.parse().map_err(|_| invalid())?,
+        color: color.parse().map_err(|_| invalid())?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        match parse_args(args.iter().map(|arg| arg.to_string()))? {
+            ParseResult::Run(args) => Ok(*args),
+            ParseResult::Help => Err("help".to_string()),
+        }
+    }
+
+    fn exit_code(args: &[&str]) -> i32 {
+        run_with_exit_code(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn options_are_parsed() {
+        let args = parse(&[
+            "-o",
+            "out.jpg",
+            "--seed",
+            "42",
+            "--quality",
+            "90",
+            "--gutter",
+            "8",
+            "--stroke",
+            "4:#ffffff",
+            "--importance",
+            "3,1",
+            "a.png",
+            "b.png",
+        ])
+        .unwrap();
+
+        assert_eq!(args.output, PathBuf::from("out.jpg"));
+        assert_eq!(
+            args.inputs,
+            vec![PathBuf::from("a.png"), PathBuf::from("b.png")]
+        );
+        assert_eq!(args.seed, Some(42));
+        assert_eq!(args.options.output.format, OutputFormat::Jpeg);
+        assert_eq!(args.options.output.quality, 90);
+        assert_eq!(args.options.layout.gutter, 8);
+        assert_eq!(args.options.render.tile.stroke.unwrap().width, 4);
+        assert_eq!(args.options.importance, vec![3.0, 1.0]);
+    }
+
+    #[test]
+    fn format_flag_overrides_the_extension() {
+        let args = parse(&["-o", "out.jpg", "-f", "png", "a.png", "b.png"]).unwrap();
+
+        assert_eq!(args.options.output.format, OutputFormat::Png);
+    }
+
+    #[test]
+    fn help_is_recognised_anywhere() {
+        assert!(matches!(
+            parse_args(["a.png", "--help"].iter().map(|arg| arg.to_string())),
+            Ok(ParseResult::Help)
+        ));
+    }
+
+    #[test]
+    fn invalid_options_are_rejected() {
+        for args in [
+            &["a.png", "b.png"][..],
+            &["-o", "out.jpg", "a.png"],
+            &["-o", "out.jpg", "a.png", "b.png", "--quality", "0"],
+            &["-o", "out.jpg", "a.png", "b.png", "--quality"],
+            &["-o", "out.jpg", "a.png", "b.png", "--unknown"],
+            &["-o", "out", "a.png", "b.png"],
+            &["-o", "out.jpg", "a.png", "b.png", "--population", "2"],
+        ] {
+            assert!(parse(args).is_err(), "{:?} was accepted", args);
+        }
+    }
+
+    #[test]
+    fn conflicting_flags_are_rejected() {
+        for args in [
+            &["--justified-rows", "1200", "--masonry", "3"][..],
+            &["--row-height", "200"],
+            &["--column-width", "200"],
+            &["--trim"],
+            &["--masonry", "3", "--blueprint", "layout.json"],
+            &[
+                "--justified-rows",
+                "1200",
+                "--save-blueprint",
+                "layout.json",
+            ],
+        ] {
+            let args = [&["-o", "out.jpg", "a.png", "b.png"][..], args].concat();
+
+            assert!(parse(&args).is_err(), "{:?} was accepted", args);
+        }
+    }
+
+    #[test]
+    fn invalid_arguments_exit_with_usage_error() {
+        assert_eq!(
+            exit_code(&["-o", "out.jpg", "--masonry", "0", "a.png", "b.png"]),
+            EXIT_USAGE
+        );
+    }
+
+    #[test]
+    fn failures_after_parsing_exit_with_failure() {
+        let missing = std::env::temp_dir().join("collage-test-missing.png");
+        let missing = missing.to_str().unwrap();
+
+        assert_eq!(
+            exit_code(&["-o", "out.jpg", missing, missing]),
+            EXIT_FAILURE
+        );
+    }
+
+    #[test]
+    fn help_exits_successfully() {
+        assert_eq!(exit_code(&["--help"]), 0);
+    }
 }
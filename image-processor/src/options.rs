@@ -0,0 +1,1038 @@
+use crate::cost::{CostTerm, WeightedCost, WeightedTerm};
+use crate::error::CollageError;
+use crate::exact;
+use crate::gallery::Gallery;
+use crate::layout::Dimensions;
+use crate::output::{OutputFormat, OutputOptions};
+use crate::strategy::{Strategy, PARTITION_THRESHOLD};
+use crate::templates::Template;
+use serde::Deserialize;
+use std::convert::TryFrom;
+
+// Everything that can be tweaked about a collage. Every field is optional when deserializing, so
+// JS can pass just the parts it cares about, e.g. `{output: {format: "png"}}`.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct CollageOptions {
+    pub output: OutputOptions,
+    pub layout: LayoutOptions,
+    pub render: RenderOptions,
+    pub genetic: GeneticParams,
+    // What makes one layout better than another, see the cost module.
+    pub cost: WeightedCost,
+    // Relative importance of each image, in the order they were given in, e.g. `[3, 1, 1]` asks
+    // for the first image to take up three times as much of the collage as each of the others.
+    // Empty if all images are equally important.
+    pub importance: Vec<f64>,
+    // How to come up with the layout, e.g. "partition", see the strategy module.
+    pub strategy: Strategy,
+    // Lays the images out as a gallery, e.g. in justified rows, instead of making a collage out of
+    // them, see the gallery module.
+    pub gallery: Option<Gallery>,
+    // The part of each image, in the order they were given in, that stays in view when it's
+    // cropped, e.g. `[{x: 0.5, y: 0.25}, ...]` for a face in the upper half. Empty if all images
+    // are cropped around their middle.
+    pub focal_points: Vec<FocalPoint>,
+}
+
+impl CollageOptions {
+    pub fn validate(&self) -> Result<(), CollageError> {
+        self.layout.validate()?;
+        self.genetic.validate()?;
+        self.cost.validate()?;
+        self.render.tile.validate()?;
+
+        if let Some(gallery) = &self.gallery {
+            gallery.validate(&self.layout)?;
+
+            if self.layout.template.is_some() {
+                return Err(CollageError::InvalidOptions(
+                    "A gallery can't be combined with a template".to_string(),
+                ));
+            }
+
+            if !self.importance.is_empty() {
+                return Err(CollageError::InvalidOptions(
+                    "Galleries keep the images at the same height or width, so they can't take \
+                     their importance into account"
+                        .to_string(),
+                ));
+            }
+        }
+
+        if self.layout.template.is_some() && !self.importance.is_empty() {
+            return Err(CollageError::InvalidOptions(
+                "Templates have fixed proportions, so they can't take the importance of the \
+                 images into account"
+                    .to_string(),
+            ));
+        }
+
+        if !self
+            .importance
+            .iter()
+            .all(|weight| *weight > 0.0 && weight.is_finite())
+        {
+            return Err(CollageError::InvalidOptions(format!(
+                "Image importance must be a positive number, got {:?}",
+                self.importance
+            )));
+        }
+
+        if let Some(focal_point) = self.focal_points.iter().find(|point| !point.is_valid()) {
+            return Err(CollageError::InvalidOptions(format!(
+                "Focal points must be between 0 and 1, got {:?}",
+                focal_point
+            )));
+        }
+
+        // Checked before the search rather than when encoding, so as not to waste it.
+        self.output.validate()?;
+
+        if self.output.format == OutputFormat::Jpeg && !self.render.background.is_opaque() {
+            return Err(CollageError::InvalidOptions(
+                "JPEG doesn't support transparency, use PNG or WebP for a transparent background"
+                    .to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    // The cost function to search for a layout with, which also takes the importance of the
+    // images into account if it was given.
+    pub fn cost_function(&self, image_count: usize) -> Result<WeightedCost, CollageError> {
+        if self.importance.is_empty() {
+            return Ok(self.cost.clone());
+        }
+
+        if self.importance.len() != image_count {
+            return Err(CollageError::InvalidOptions(format!(
+                "Expected the importance of {} images, got {}",
+                image_count,
+                self.importance.len()
+            )));
+        }
+
+        let mut cost = self.cost.clone();
+
+        // The scale deviation pulls every image towards its original size and grows with the
+        // number of images, so the importance has to keep up with it to make a difference.
+        cost.terms.push(WeightedTerm {
+            weight: image_count as f64,
+            term: CostTerm::Importance {
+                weights: self.importance.clone(),
+            },
+        });
+
+        Ok(cost)
+    }
+
+    // The strategy to lay out the given number of images with, see `Strategy::resolve`. The
+    // partition strategy splits the images by their number rather than by their area, so only
+    // the other ones can take the importance of the images into account.
+    pub fn strategy(&self, image_count: usize) -> Result<Strategy, CollageError> {
+        let strategy = self.strategy.resolve(image_count, &self.genetic);
+
+        if strategy == Strategy::Partition && !self.importance.is_empty() {
+            return Err(CollageError::InvalidOptions(format!(
+                "The partition strategy, which is the default for {} images or more, can't take \
+                 the importance of the images into account, use the genetic strategy instead",
+                PARTITION_THRESHOLD
+            )));
+        }
+
+        Ok(strategy)
+    }
+
+    // The focal point of each image, or an empty list if none were given.
+    pub fn focal_points(&self, image_count: usize) -> Result<&[FocalPoint], CollageError> {
+        if !self.focal_points.is_empty() && self.focal_points.len() != image_count {
+            return Err(CollageError::InvalidOptions(format!(
+                "Expected the focal points of {} images, got {}",
+                image_count,
+                self.focal_points.len()
+            )));
+        }
+
+        Ok(&self.focal_points)
+    }
+}
+
+// How hard the genetic algorithm tries to find a good layout. Deserializes either from the name of
+// a preset, e.g. "fast", or from an object which overrides some parameters of a preset, e.g.
+// `{preset: "thorough", generation_limit: 10000}`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(try_from = "GeneticParamsRepresentation")]
+pub struct GeneticParams {
+    pub population_size: usize,
+    pub generation_limit: u64,
+    // Share of the population picked as parents in each generation.
+    pub selection_ratio: f64,
+    // Share of the offspring which replaces the worst individuals of the population.
+    pub reinsertion_ratio: f64,
+    // Stops the search after this much wall-clock time even if there are generations left. The
+    // best layout found so far is used.
+    pub time_limit_ms: Option<u64>,
+    // Stops the search after this many generations in a row in which the best cost didn't
+    // improve by more than the epsilon.
+    pub stagnation_limit: Option<u64>,
+    pub stagnation_epsilon: f64,
+    // With the auto strategy, collages with at most this many images skip the genetic algorithm
+    // and get the best layout by trying all of them instead, see the exact module. 7 at most, as
+    // it takes seconds already.
+    pub exact_search_limit: usize,
+}
+
+// genevo refuses to run with a smaller population.
+pub const MIN_POPULATION_SIZE: usize = 6;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum GeneticPreset {
+    Fast,
+    #[default]
+    Balanced,
+    Thorough,
+}
+
+impl GeneticPreset {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "fast" => Some(GeneticPreset::Fast),
+            "balanced" => Some(GeneticPreset::Balanced),
+            "thorough" => Some(GeneticPreset::Thorough),
+            _ => None,
+        }
+    }
+}
+
+impl GeneticParams {
+    // Fast and thorough match what debug and release builds used to do respectively.
+    pub fn preset(preset: GeneticPreset) -> Self {
+        let (population_size, generation_limit, stagnation_limit, exact_search_limit) = match preset
+        {
+            GeneticPreset::Fast => (50, 200, 50, 5),
+            GeneticPreset::Balanced => (75, 1_000, 150, 6),
+            GeneticPreset::Thorough => (75, 4_000, 500, 6),
+        };
+
+        GeneticParams {
+            population_size,
+            generation_limit,
+            selection_ratio: 0.7,
+            reinsertion_ratio: 0.7,
+            time_limit_ms: None,
+            stagnation_limit: Some(stagnation_limit),
+            stagnation_epsilon: 1e-4,
+            exact_search_limit,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), CollageError> {
+        if self.population_size < MIN_POPULATION_SIZE {
+            return Err(CollageError::InvalidOptions(format!(
+                "Population size must be at least {}, got {}",
+                MIN_POPULATION_SIZE, self.population_size
+            )));
+        }
+
+        if self.generation_limit == 0
+            || self.time_limit_ms == Some(0)
+            || self.stagnation_limit == Some(0)
+        {
+            return Err(CollageError::InvalidOptions(
+                "Generation, time and stagnation limits must be greater than zero".to_string(),
+            ));
+        }
+
+        if !(self.stagnation_epsilon >= 0.0 && self.stagnation_epsilon.is_finite()) {
+            return Err(CollageError::InvalidOptions(format!(
+                "Stagnation epsilon must be a non-negative number, got {}",
+                self.stagnation_epsilon
+            )));
+        }
+
+        if self.exact_search_limit > exact::MAX_IMAGES {
+            return Err(CollageError::InvalidOptions(format!(
+                "The exact search supports at most {} images, got a limit of {}",
+                exact::MAX_IMAGES,
+                self.exact_search_limit
+            )));
+        }
+
+        for (name, ratio) in [
+            ("Selection", self.selection_ratio),
+            ("Reinsertion", self.reinsertion_ratio),
+        ] {
+            if !(ratio > 0.0 && ratio <= 1.0) {
+                return Err(CollageError::InvalidOptions(format!(
+                    "{} ratio must be greater than 0 and at most 1, got {}",
+                    name, ratio
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for GeneticParams {
+    fn default() -> Self {
+        GeneticParams::preset(GeneticPreset::default())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum GeneticParamsRepresentation {
+    Preset(GeneticPreset),
+    Custom {
+        #[serde(default)]
+        preset: GeneticPreset,
+        population_size: Option<usize>,
+        generation_limit: Option<u64>,
+        selection_ratio: Option<f64>,
+        reinsertion_ratio: Option<f64>,
+        time_limit_ms: Option<u64>,
+        // `null` turns off the stagnation detection, hence the nested option.
+        #[serde(default, deserialize_with = "deserialize_some")]
+        stagnation_limit: Option<Option<u64>>,
+        stagnation_epsilon: Option<f64>,
+        exact_search_limit: Option<usize>,
+    },
+}
+
+impl TryFrom<GeneticParamsRepresentation> for GeneticParams {
+    type Error = CollageError;
+
+    fn try_from(representation: GeneticParamsRepresentation) -> Result<Self, Self::Error> {
+        let params = match representation {
+            GeneticParamsRepresentation::Preset(preset) => GeneticParams::preset(preset),
+            GeneticParamsRepresentation::Custom {
+                preset,
+                population_size,
+                generation_limit,
+                selection_ratio,
+                reinsertion_ratio,
+                time_limit_ms,
+                stagnation_limit,
+                stagnation_epsilon,
+                exact_search_limit,
+            } => {
+                let defaults = GeneticParams::preset(preset);
+
+                GeneticParams {
+                    population_size: population_size.unwrap_or(defaults.population_size),
+                    generation_limit: generation_limit.unwrap_or(defaults.generation_limit),
+                    selection_ratio: selection_ratio.unwrap_or(defaults.selection_ratio),
+                    reinsertion_ratio: reinsertion_ratio.unwrap_or(defaults.reinsertion_ratio),
+                    time_limit_ms: time_limit_ms.or(defaults.time_limit_ms),
+                    stagnation_limit: stagnation_limit.unwrap_or(defaults.stagnation_limit),
+                    stagnation_epsilon: stagnation_epsilon.unwrap_or(defaults.stagnation_epsilon),
+                    exact_search_limit: exact_search_limit.unwrap_or(defaults.exact_search_limit),
+                }
+            }
+        };
+
+        params.validate()?;
+
+        Ok(params)
+    }
+}
+
+// Tells a missing field, which gets the default, apart from an explicit `null`.
+fn deserialize_some<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: serde::Deserializer<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+// Options which only matter when drawing the collage and have no effect on the layout itself.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(default)]
+pub struct RenderOptions {
+    // Shows through gutters, margins, letterboxing and transparent parts of the images.
+    pub background: Color,
+    pub tile: TileStyle,
+}
+
+// Applied to every image in the collage, e.g.
+// `{corner_radius: 12, stroke: {width: 2, color: "#ffffff"}, shadow: {}}`.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(default)]
+pub struct TileStyle {
+    // In pixels, capped at half of the shorter side of the tile.
+    pub corner_radius: u32,
+    pub stroke: Option<Stroke>,
+    pub shadow: Option<Shadow>,
+}
+
+// Limits for the parts of the style given in pixels. Shadows are drawn through a mask that grows
+// with the blur, so they need to stay reasonable, the rest is there to catch typos.
+pub const MAX_BLUR: u32 = 100;
+pub const MAX_SHADOW_OFFSET: i32 = 1_000;
+pub const MAX_STROKE_WIDTH: u32 = 1_000;
+
+impl TileStyle {
+    pub fn is_plain(&self) -> bool {
+        *self == TileStyle::default()
+    }
+
+    pub fn validate(&self) -> Result<(), CollageError> {
+        if let Some(stroke) = self.stroke {
+            if stroke.width > MAX_STROKE_WIDTH {
+                return Err(CollageError::InvalidOptions(format!(
+                    "Stroke width must be at most {}, got {}",
+                    MAX_STROKE_WIDTH, stroke.width
+                )));
+            }
+        }
+
+        if let Some(shadow) = self.shadow {
+            if shadow.blur > MAX_BLUR {
+                return Err(CollageError::InvalidOptions(format!(
+                    "Shadow blur must be at most {}, got {}",
+                    MAX_BLUR, shadow.blur
+                )));
+            }
+
+            let offset_range = -MAX_SHADOW_OFFSET..=MAX_SHADOW_OFFSET;
+            if !offset_range.contains(&shadow.offset_x) || !offset_range.contains(&shadow.offset_y)
+            {
+                return Err(CollageError::InvalidOptions(format!(
+                    "Shadow offsets must be between -{} and {}, got {} and {}",
+                    MAX_SHADOW_OFFSET, MAX_SHADOW_OFFSET, shadow.offset_x, shadow.offset_y
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Drawn on the inside of the tile, so that it doesn't cover the gutters.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Stroke {
+    pub width: u32,
+    pub color: Color,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct Shadow {
+    pub offset_x: i32,
+    pub offset_y: i32,
+    // How far in pixels the shadow fades out past the edges of the tile.
+    pub blur: u32,
+    pub color: Color,
+}
+
+impl Default for Shadow {
+    fn default() -> Self {
+        Shadow {
+            offset_x: 0,
+            offset_y: 4,
+            blur: 12,
+            color: Color([0, 0, 0, 128]),
+        }
+    }
+}
+
+// Constraints that the layout and the renderer both need to know about.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(default)]
+pub struct LayoutOptions {
+    // Upper bounds for the size of the final collage. The collage is scaled down to fit, never up.
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    pub max_megapixels: Option<f64>,
+    // Locks the canvas to the given shape. Without it, the canvas can take any shape that fits the
+    // images best.
+    pub aspect_ratio: Option<AspectRatio>,
+    // Space in pixels between adjacent images.
+    pub gutter: u32,
+    // Space in pixels between the images and the edges of the collage.
+    pub margin: u32,
+    // Uses a classic layout such as "grid" instead of generating one, see the templates module.
+    pub template: Option<Template>,
+    // How far the shape of each tile may be from the shape of its image, e.g. 0.25 lets a 4:3
+    // image go anywhere between a 1.07:1 and a 1.67:1 tile. The image is cropped to fill the tile,
+    // so that the layout fills the canvas. Zero keeps the images whole.
+    pub crop_tolerance: f64,
+}
+
+impl LayoutOptions {
+    pub fn validate(&self) -> Result<(), CollageError> {
+        if self.max_width == Some(0) || self.max_height == Some(0) {
+            return Err(CollageError::InvalidOptions(
+                "Maximum width and height must be greater than zero".to_string(),
+            ));
+        }
+
+        if let Some(max_megapixels) = self.max_megapixels {
+            if !(max_megapixels > 0.0 && max_megapixels.is_finite()) {
+                return Err(CollageError::InvalidOptions(format!(
+                    "Maximum megapixels must be a positive number, got {}",
+                    max_megapixels
+                )));
+            }
+        }
+
+        if !(0.0..=1.0).contains(&self.crop_tolerance) {
+            return Err(CollageError::InvalidOptions(format!(
+                "Crop tolerance must be between 0 and 1, got {}",
+                self.crop_tolerance
+            )));
+        }
+
+        Ok(())
+    }
+
+    // Two images are normally just stitched together, which leaves no room for any of these.
+    pub fn needs_layout(&self) -> bool {
+        self.aspect_ratio.is_some() || self.gutter > 0 || self.margin > 0
+    }
+
+    // The factor by which both sides need to be multiplied to fit within the limits. Never greater
+    // than 1.
+    pub fn scale_to_fit(&self, dimensions: Dimensions) -> f64 {
+        let mut scale: f64 = 1.0;
+
+        if let Some(max_width) = self.max_width {
+            scale = scale.min(max_width as f64 / dimensions.width as f64);
+        }
+
+        if let Some(max_height) = self.max_height {
+            scale = scale.min(max_height as f64 / dimensions.height as f64);
+        }
+
+        if let Some(max_megapixels) = self.max_megapixels {
+            scale = scale.min((max_megapixels * 1_000_000.0 / dimensions.size() as f64).sqrt());
+        }
+
+        scale
+    }
+
+    // Derives the height from the width if the aspect ratio is locked.
+    pub fn lock_aspect_ratio(&self, dimensions: Dimensions) -> Dimensions {
+        match self.aspect_ratio {
+            Some(aspect_ratio) => Dimensions {
+                width: dimensions.width,
+                height: ((dimensions.width as f64 / aspect_ratio.value()).round() as u32).max(1),
+            },
+            None => dimensions,
+        }
+    }
+
+    pub fn fit(&self, dimensions: Dimensions) -> Dimensions {
+        let scale = self.scale_to_fit(dimensions);
+
+        if scale >= 1.0 {
+            return dimensions;
+        }
+
+        Dimensions {
+            width: ((dimensions.width as f64 * scale) as u32).max(1),
+            height: ((dimensions.height as f64 * scale) as u32).max(1),
+        }
+    }
+
+    // Rough estimate of how many pixels the collage can have at most. With just one side limited,
+    // we assume a square canvas, as we don't know the aspect ratio of the layout upfront.
+    pub fn max_size(&self) -> Option<f64> {
+        let by_sides = match (self.max_width, self.max_height) {
+            (Some(width), Some(height)) => Some(width as f64 * height as f64),
+            (Some(side), None) | (None, Some(side)) => Some(side as f64 * side as f64),
+            (None, None) => None,
+        };
+        let by_megapixels = self
+            .max_megapixels
+            .map(|max_megapixels| max_megapixels * 1_000_000.0);
+
+        match (by_sides, by_megapixels) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+// Width divided by height. Deserializes either from a number or from a string such as "16:9".
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(try_from = "AspectRatioRepresentation")]
+pub struct AspectRatio(f64);
+
+impl AspectRatio {
+    pub fn new(value: f64) -> Result<Self, CollageError> {
+        if value > 0.0 && value.is_finite() {
+            Ok(AspectRatio(value))
+        } else {
+            Err(CollageError::InvalidOptions(format!(
+                "Aspect ratio must be a positive number, got {}",
+                value
+            )))
+        }
+    }
+
+    pub fn value(&self) -> f64 {
+        self.0
+    }
+}
+
+impl std::str::FromStr for AspectRatio {
+    type Err = CollageError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || CollageError::InvalidOptions(format!("Invalid aspect ratio: {:?}", s));
+
+        match s.split_once(':') {
+            Some((width, height)) => {
+                let width: f64 = width.trim().parse().map_err(|_| invalid())?;
+                let height: f64 = height.trim().parse().map_err(|_| invalid())?;
+
+                AspectRatio::new(width / height)
+            }
+            None => AspectRatio::new(s.trim().parse().map_err(|_| invalid())?),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AspectRatioRepresentation {
+    Number(f64),
+    Text(String),
+}
+
+impl TryFrom<AspectRatioRepresentation> for AspectRatio {
+    type Error = CollageError;
+
+    fn try_from(representation: AspectRatioRepresentation) -> Result<Self, Self::Error> {
+        match representation {
+            AspectRatioRepresentation::Number(value) => AspectRatio::new(value),
+            AspectRatioRepresentation::Text(text) => text.parse(),
+        }
+    }
+}
+
+// A point in an image, relative to its size, so `{x: 0.5, y: 0.5}` is the middle of any image.
+// Parses from "X:Y", e.g. "0.5:0.25".
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct FocalPoint {
+    pub x: f64,
+    pub y: f64,
+}
+
+impl FocalPoint {
+    pub const CENTER: FocalPoint = FocalPoint { x: 0.5, y: 0.5 };
+
+    pub fn is_valid(&self) -> bool {
+        (0.0..=1.0).contains(&self.x) && (0.0..=1.0).contains(&self.y)
+    }
+}
+
+impl Default for FocalPoint {
+    fn default() -> Self {
+        FocalPoint::CENTER
+    }
+}
+
+impl std::str::FromStr for FocalPoint {
+    type Err = CollageError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || CollageError::InvalidOptions(format!("Invalid focal point: {:?}", s));
+        let (x, y) = s.split_once(':').ok_or_else(invalid)?;
+        let focal_point = FocalPoint {
+            x: x.trim().parse().map_err(|_| invalid())?,
+            y: y.trim().parse().map_err(|_| invalid())?,
+        };
+
+        if focal_point.is_valid() {
+            Ok(focal_point)
+        } else {
+            Err(invalid())
+        }
+    }
+}
+
+// An RGBA colour. Deserializes from "#rrggbb", "#rrggbbaa" or "transparent".
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Color(pub [u8; 4]);
+
+impl Color {
+    pub const BLACK: Color = Color([0, 0, 0, 255]);
+    pub const TRANSPARENT: Color = Color([0, 0, 0, 0]);
+
+    pub fn is_opaque(&self) -> bool {
+        self.0[3] == u8::MAX
+    }
+
+    pub fn to_rgba(self) -> image::Rgba<u8> {
+        image::Rgba(self.0)
+    }
+}
+
+// Uncovered areas used to be always black, so that's what we keep as the default.
+impl Default for Color {
+    fn default() -> Self {
+        Color::BLACK
+    }
+}
+
+impl std::str::FromStr for Color {
+    type Err = CollageError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || CollageError::InvalidOptions(format!("Invalid colour: {:?}", s));
+
+        if s.eq_ignore_ascii_case("transparent") {
+            return Ok(Color::TRANSPARENT);
+        }
+
+        let hex = s.strip_prefix('#').ok_or_else(invalid)?;
+
+        if !(hex.len() == 6 || hex.len() == 8) || !hex.is_ascii() {
+            return Err(invalid());
+        }
+
+        let mut channels = [u8::MAX; 4];
+
+        for (i, channel) in channels.iter_mut().enumerate().take(hex.len() / 2) {
+            *channel = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+        }
+
+        Ok(Color(channels))
+    }
+}
+
+impl TryFrom<String> for Color {
+    type Error = CollageError;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        text.parse()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fitting_within_max_width_keeps_aspect_ratio() {
+        let options = LayoutOptions {
+            max_width: Some(100),
+            ..LayoutOptions::default()
+        };
+
+        assert_eq!(
+            Dimensions::from_tuple((100, 50)),
+            options.fit(Dimensions::from_tuple((400, 200)))
+        );
+    }
+
+    #[test]
+    fn fitting_picks_the_strictest_limit() {
+        let options = LayoutOptions {
+            max_width: Some(1000),
+            max_height: Some(1000),
+            max_megapixels: Some(0.01),
+            ..LayoutOptions::default()
+        };
+
+        assert_eq!(
+            Dimensions::from_tuple((100, 100)),
+            options.fit(Dimensions::from_tuple((2000, 2000)))
+        );
+    }
+
+    #[test]
+    fn fitting_never_scales_up() {
+        let options = LayoutOptions {
+            max_width: Some(1000),
+            max_megapixels: Some(10.0),
+            ..LayoutOptions::default()
+        };
+
+        assert_eq!(
+            Dimensions::from_tuple((30, 20)),
+            options.fit(Dimensions::from_tuple((30, 20)))
+        );
+    }
+
+    #[test]
+    fn non_positive_limits_are_invalid() {
+        let zero_width = LayoutOptions {
+            max_width: Some(0),
+            ..LayoutOptions::default()
+        };
+        let negative_megapixels = LayoutOptions {
+            max_megapixels: Some(-1.0),
+            ..LayoutOptions::default()
+        };
+
+        assert!(zero_width.validate().is_err());
+        assert!(negative_megapixels.validate().is_err());
+    }
+
+    #[test]
+    fn parsing_aspect_ratios() {
+        assert_eq!(16.0 / 9.0, "16:9".parse::<AspectRatio>().unwrap().value());
+        assert_eq!(0.8, "4:5".parse::<AspectRatio>().unwrap().value());
+        assert_eq!(1.5, "1.5".parse::<AspectRatio>().unwrap().value());
+        assert!("16:0".parse::<AspectRatio>().is_err());
+        assert!("wide".parse::<AspectRatio>().is_err());
+    }
+
+    #[test]
+    fn locking_aspect_ratio_derives_height_from_width() {
+        let options = LayoutOptions {
+            aspect_ratio: Some("16:9".parse().unwrap()),
+            ..LayoutOptions::default()
+        };
+
+        assert_eq!(
+            Dimensions::from_tuple((1600, 900)),
+            options.lock_aspect_ratio(Dimensions::from_tuple((1600, 100)))
+        );
+    }
+
+    #[test]
+    fn crop_tolerance_is_limited() {
+        let options = |crop_tolerance| LayoutOptions {
+            crop_tolerance,
+            ..LayoutOptions::default()
+        };
+
+        assert!(options(0.0).validate().is_ok());
+        assert!(options(1.0).validate().is_ok());
+        assert!(options(-0.1).validate().is_err());
+        assert!(options(f64::NAN).validate().is_err());
+    }
+
+    #[test]
+    fn parsing_focal_points() {
+        assert_eq!(
+            FocalPoint { x: 0.5, y: 0.25 },
+            "0.5:0.25".parse::<FocalPoint>().unwrap()
+        );
+        assert!("0.5".parse::<FocalPoint>().is_err());
+        assert!("1.5:0".parse::<FocalPoint>().is_err());
+    }
+
+    #[test]
+    fn parsing_colours() {
+        assert_eq!(Color([255, 128, 0, 255]), "#ff8000".parse().unwrap());
+        assert_eq!(Color([255, 128, 0, 64]), "#FF800040".parse().unwrap());
+        assert_eq!(Color::TRANSPARENT, "transparent".parse().unwrap());
+        assert!("ff8000".parse::<Color>().is_err());
+        assert!("#ff80".parse::<Color>().is_err());
+        assert!("#gg8000".parse::<Color>().is_err());
+    }
+
+    #[test]
+    fn genetic_params_from_a_preset_name() {
+        let params: GeneticParams = serde_json::from_value(serde_json::json!("fast")).unwrap();
+
+        assert_eq!(GeneticParams::preset(GeneticPreset::Fast), params);
+    }
+
+    #[test]
+    fn genetic_params_override_parts_of_a_preset() {
+        let params: GeneticParams = serde_json::from_value(serde_json::json!({
+            "preset": "thorough",
+            "population_size": 20,
+        }))
+        .unwrap();
+
+        assert_eq!(
+            GeneticParams {
+                population_size: 20,
+                ..GeneticParams::preset(GeneticPreset::Thorough)
+            },
+            params
+        );
+        assert!(serde_json::from_value::<GeneticParams>(serde_json::json!({
+            "population_size": 1,
+        }))
+        .is_err());
+    }
+
+    #[test]
+    fn population_needs_to_be_large_enough_for_genevo() {
+        let params = |population_size| GeneticParams {
+            population_size,
+            ..GeneticParams::default()
+        };
+
+        assert!(params(MIN_POPULATION_SIZE - 1).validate().is_err());
+        assert!(params(MIN_POPULATION_SIZE).validate().is_ok());
+    }
+
+    #[test]
+    fn exact_search_is_limited_to_small_collages() {
+        let params = |limit| GeneticParams {
+            exact_search_limit: limit,
+            ..GeneticParams::default()
+        };
+
+        assert!(params(0).validate().is_ok());
+        assert!(params(exact::MAX_IMAGES).validate().is_ok());
+        assert!(params(exact::MAX_IMAGES + 1).validate().is_err());
+    }
+
+    #[test]
+    fn stagnation_detection_can_be_turned_off() {
+        let params: GeneticParams = serde_json::from_value(serde_json::json!({
+            "stagnation_limit": null,
+        }))
+        .unwrap();
+
+        assert_eq!(None, params.stagnation_limit);
+        assert!(GeneticParams::default().stagnation_limit.is_some());
+    }
+
+    #[test]
+    fn jpeg_quality_is_checked_with_the_other_options() {
+        let options = |format, quality| CollageOptions {
+            output: OutputOptions {
+                format,
+                quality,
+                ..OutputOptions::default()
+            },
+            ..CollageOptions::default()
+        };
+
+        assert!(options(OutputFormat::Jpeg, 0).validate().is_err());
+        assert!(options(OutputFormat::Jpeg, 101).validate().is_err());
+        assert!(options(OutputFormat::Jpeg, 100).validate().is_ok());
+        assert!(options(OutputFormat::Png, 0).validate().is_ok());
+    }
+
+    #[test]
+    fn transparent_background_is_rejected_for_jpeg() {
+        let options = CollageOptions {
+            render: RenderOptions {
+                background: Color::TRANSPARENT,
+                ..RenderOptions::default()
+            },
+            ..CollageOptions::default()
+        };
+
+        assert!(options.validate().is_err());
+    }
+
+    #[test]
+    fn oversized_tile_styles_are_rejected() {
+        let options = |tile| CollageOptions {
+            render: RenderOptions {
+                tile,
+                ..RenderOptions::default()
+            },
+            ..CollageOptions::default()
+        };
+        let shadow = |offset_x, blur| TileStyle {
+            shadow: Some(Shadow {
+                offset_x,
+                blur,
+                ..Shadow::default()
+            }),
+            ..TileStyle::default()
+        };
+        let stroke = |width| TileStyle {
+            stroke: Some(Stroke {
+                width,
+                color: Color::default(),
+            }),
+            ..TileStyle::default()
+        };
+
+        assert!(options(shadow(0, MAX_BLUR)).validate().is_ok());
+        assert!(options(shadow(0, MAX_BLUR + 1)).validate().is_err());
+        assert!(options(shadow(0, u32::MAX)).validate().is_err());
+        assert!(options(shadow(-MAX_SHADOW_OFFSET, 0)).validate().is_ok());
+        assert!(options(shadow(-MAX_SHADOW_OFFSET - 1, 0))
+            .validate()
+            .is_err());
+        assert!(options(stroke(MAX_STROKE_WIDTH)).validate().is_ok());
+        assert!(options(stroke(MAX_STROKE_WIDTH + 1)).validate().is_err());
+    }
+
+    #[test]
+    fn importance_is_added_to_the_cost_function() {
+        let options = CollageOptions {
+            importance: vec![3.0, 1.0, 1.0],
+            ..CollageOptions::default()
+        };
+        let cost = options.cost_function(3).unwrap();
+
+        assert_eq!(
+            Some(&WeightedTerm {
+                weight: 3.0,
+                term: CostTerm::Importance {
+                    weights: vec![3.0, 1.0, 1.0]
+                }
+            }),
+            cost.terms.last()
+        );
+        assert_eq!(
+            WeightedCost::default(),
+            CollageOptions::default().cost_function(3).unwrap()
+        );
+    }
+
+    #[test]
+    fn importance_has_to_be_given_for_every_image() {
+        let options = CollageOptions {
+            importance: vec![3.0, 1.0],
+            ..CollageOptions::default()
+        };
+
+        assert!(options.validate().is_ok());
+        assert!(options.cost_function(3).is_err());
+        assert!(CollageOptions {
+            importance: vec![3.0, 0.0],
+            ..CollageOptions::default()
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn importance_is_rejected_where_it_would_be_ignored() {
+        let options = CollageOptions {
+            importance: vec![3.0, 1.0, 1.0],
+            ..CollageOptions::default()
+        };
+
+        assert!(CollageOptions {
+            layout: LayoutOptions {
+                template: Some(Template::Grid),
+                ..LayoutOptions::default()
+            },
+            ..options.clone()
+        }
+        .validate()
+        .is_err());
+        assert!(CollageOptions {
+            strategy: Strategy::Partition,
+            ..options.clone()
+        }
+        .strategy(3)
+        .is_err());
+        assert!(options.strategy(PARTITION_THRESHOLD).is_err());
+        assert_eq!(
+            Strategy::Genetic,
+            options.strategy(PARTITION_THRESHOLD - 1).unwrap()
+        );
+        assert_eq!(
+            Strategy::Partition,
+            CollageOptions::default()
+                .strategy(PARTITION_THRESHOLD)
+                .unwrap()
+        );
+    }
+}
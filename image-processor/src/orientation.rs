@@ -1,31 +1,41 @@
 use exif::{In, Tag};
-use image::{imageops, RgbImage};
+use image::{imageops, RgbaImage};
 use std::io::Cursor;
-use web_sys::console;
 
-pub fn fix_if_needed(raw_image: &[u8]) -> RgbImage {
+use crate::error::CollageError;
+use crate::utils::Timer;
+
+pub fn fix_if_needed(raw_image: &[u8]) -> Result<RgbaImage, CollageError> {
     let orientation = get_orientation(raw_image);
 
-    console::time_with_label("converting into RgbImage");
-    let image = image::load_from_memory(raw_image).unwrap().into_rgb8();
-    console::time_end_with_label("converting into RgbImage");
+    let timer = Timer::start("converting into RgbaImage");
+    let image = image::load_from_memory(raw_image)
+        .map_err(CollageError::from_decoding_error)?
+        .into_rgba8();
+    timer.stop();
 
-    match orientation {
+    let image = match orientation {
         Ok(orientation_tag) => fix_orientation(image, orientation_tag),
         Err(reason) => {
             log_reason_for_no_orientation_fix(reason);
             image
         }
-    }
+    };
+
+    Ok(image)
 }
 
+// A broken EXIF block doesn't make the image itself unusable, so `InvalidExif` is logged rather
+// than returned to the caller and the image is used as it is. That's on purpose, and the reason
+// why CollageError has no variant for EXIF problems. The exif crate also reports formats it can't
+// look into, such as GIF and BMP, as invalid, so failing on that would reject images which
+// decode just fine.
 #[derive(Debug)]
 enum NoFixNeededReason {
     AlreadyCorrect,
-    ParsingError(String),
     NoExif,
     NoOrientationTag,
-    InvalidOrientationTagValue(Option<u32>),
+    InvalidExif(String),
 }
 
 fn get_orientation(raw_image: &[u8]) -> Result<u32, NoFixNeededReason> {
@@ -35,7 +45,7 @@ fn get_orientation(raw_image: &[u8]) -> Result<u32, NoFixNeededReason> {
         .read_from_container(&mut cursor)
         .map_err(|err| match err {
             exif::Error::NotFound(_) => NoFixNeededReason::NoExif,
-            _ => NoFixNeededReason::ParsingError(format!("{:?}", err)),
+            _ => NoFixNeededReason::InvalidExif(err.to_string()),
         })?;
     let exif_field = exif_data
         .get_field(Tag::Orientation, In::PRIMARY)
@@ -44,24 +54,28 @@ fn get_orientation(raw_image: &[u8]) -> Result<u32, NoFixNeededReason> {
     match exif_field.value.get_uint(0) {
         Some(1) => Err(NoFixNeededReason::AlreadyCorrect),
         Some(value @ 2..=8) => Ok(value),
-        other => Err(NoFixNeededReason::InvalidOrientationTagValue(other)),
+        other => Err(NoFixNeededReason::InvalidExif(format!(
+            "invalid orientation tag value {:?}",
+            other
+        ))),
     }
 }
 
 fn log_reason_for_no_orientation_fix(reason: NoFixNeededReason) {
     use NoFixNeededReason::*;
 
-    let log_function = match reason {
-        AlreadyCorrect | NoExif | NoOrientationTag => console::log_1,
-        ParsingError(_) | InvalidOrientationTagValue(_) => console::error_1,
-    };
-
-    log_function(&format!("{:?}", reason).into());
+    match reason {
+        AlreadyCorrect | NoExif | NoOrientationTag => {
+            log::debug!("Orientation not fixed: {:?}", reason)
+        }
+        InvalidExif(reason) => log::warn!("Orientation not fixed, invalid EXIF: {}", reason),
+    }
 }
 
 // Naive implementation until I figure out how to use transformation matrices with the image crate.
-fn fix_orientation(mut image: RgbImage, orientation: u32) -> RgbImage {
-    console::time_with_label("fixing orientation");
+#[allow(clippy::manual_is_multiple_of)]
+fn fix_orientation(mut image: RgbaImage, orientation: u32) -> RgbaImage {
+    let timer = Timer::start("fixing orientation");
 
     if orientation > 8 {
         return image;
@@ -80,7 +94,7 @@ fn fix_orientation(mut image: RgbImage, orientation: u32) -> RgbImage {
         imageops::flip_horizontal_in_place(&mut image);
     }
 
-    console::time_end_with_label("fixing orientation");
+    timer.stop();
 
     image
 }
@@ -0,0 +1,288 @@
+use crate::error::CollageError;
+use crate::image_for_processing::ImageForProcessing;
+use crate::utils::Timer;
+use image::RgbaImage;
+use serde::Deserialize;
+
+// How the finished collage gets encoded. Deserialized from JS objects such as
+// `{format: "jpeg", quality: 90, chroma_subsampling: "4:4:4"}`, with every field being optional.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct OutputOptions {
+    pub format: OutputFormat,
+    // JPEG only, 1 to 100.
+    pub quality: u8,
+    // JPEG only.
+    pub chroma_subsampling: ChromaSubsampling,
+}
+
+impl Default for OutputOptions {
+    fn default() -> Self {
+        OutputOptions {
+            format: OutputFormat::Jpeg,
+            quality: 75,
+            chroma_subsampling: ChromaSubsampling::Half,
+        }
+    }
+}
+
+impl OutputOptions {
+    pub fn validate(&self) -> Result<(), CollageError> {
+        if self.format == OutputFormat::Jpeg && !(1..=100).contains(&self.quality) {
+            return Err(CollageError::InvalidOptions(format!(
+                "JPEG quality must be between 1 and 100, got {}",
+                self.quality
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[serde(alias = "jpg")]
+    Jpeg,
+    Png,
+    // Always lossless.
+    WebP,
+}
+
+impl OutputFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "jpg" | "jpeg" => Some(OutputFormat::Jpeg),
+            "png" => Some(OutputFormat::Png),
+            "webp" => Some(OutputFormat::WebP),
+            _ => None,
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::Png => "image/png",
+            OutputFormat::WebP => "image/webp",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ChromaSubsampling {
+    // No subsampling, best for screenshots and text.
+    #[serde(rename = "4:4:4")]
+    Full,
+    // Halves the horizontal chroma resolution.
+    #[serde(rename = "4:2:2")]
+    Half,
+    // Halves both horizontal and vertical chroma resolution, smallest files.
+    #[serde(rename = "4:2:0")]
+    Quarter,
+}
+
+impl ChromaSubsampling {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "4:4:4" => Some(ChromaSubsampling::Full),
+            "4:2:2" => Some(ChromaSubsampling::Half),
+            "4:2:0" => Some(ChromaSubsampling::Quarter),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EncodedImage {
+    pub bytes: Vec<u8>,
+    pub mime_type: &'static str,
+}
+
+// Handed over to JS as `{bytes: Uint8Array, mime_type: string}`.
+impl From<EncodedImage> for wasm_bindgen::JsValue {
+    fn from(encoded_image: EncodedImage) -> Self {
+        let object = js_sys::Object::new();
+        let bytes = js_sys::Uint8Array::from(encoded_image.bytes.as_slice());
+
+        // Setting a property on a fresh plain object can't fail.
+        js_sys::Reflect::set(&object, &"bytes".into(), &bytes).unwrap();
+        js_sys::Reflect::set(
+            &object,
+            &"mime_type".into(),
+            &encoded_image.mime_type.into(),
+        )
+        .unwrap();
+
+        object.into()
+    }
+}
+
+pub fn encode(image: &RgbaImage, options: &OutputOptions) -> Result<EncodedImage, CollageError> {
+    let timer = Timer::start("encoding end result");
+    let mut bytes: Vec<u8> = vec![];
+
+    match options.format {
+        OutputFormat::Jpeg => encode_jpeg(image, options, &mut bytes)?,
+        OutputFormat::Png => {
+            let (pixels, color_type) = if image.is_opaque() {
+                (strip_alpha(image), image::ColorType::Rgb8)
+            } else {
+                (image.as_raw().clone(), image::ColorType::Rgba8)
+            };
+
+            image::png::PngEncoder::new(&mut bytes)
+                .encode(&pixels, image.width(), image.height(), color_type)
+                .map_err(|err| CollageError::Encode(err.to_string()))?
+        }
+        OutputFormat::WebP => {
+            let (pixels, color_type) = if image.is_opaque() {
+                (strip_alpha(image), image_webp::ColorType::Rgb8)
+            } else {
+                (image.as_raw().clone(), image_webp::ColorType::Rgba8)
+            };
+
+            image_webp::WebPEncoder::new(&mut bytes)
+                .encode(&pixels, image.width(), image.height(), color_type)
+                .map_err(|err| CollageError::Encode(err.to_string()))?
+        }
+    }
+
+    timer.stop();
+
+    Ok(EncodedImage {
+        bytes,
+        mime_type: options.format.mime_type(),
+    })
+}
+
+// An alpha channel in a fully opaque image only makes the file bigger.
+fn strip_alpha(image: &RgbaImage) -> Vec<u8> {
+    image
+        .pixels()
+        .flat_map(|pixel| [pixel[0], pixel[1], pixel[2]])
+        .collect()
+}
+
+// The JPEG encoder from the image crate doesn't let us pick the chroma subsampling, hence a
+// separate crate. The alpha channel is ignored, `CollageOptions::validate` makes sure that the
+// background is opaque.
+fn encode_jpeg(
+    image: &RgbaImage,
+    options: &OutputOptions,
+    bytes: &mut Vec<u8>,
+) -> Result<(), CollageError> {
+    use jpeg_encoder::SamplingFactor;
+
+    options.validate()?;
+
+    // JPEG stores dimensions as 16-bit integers.
+    let (width, height) = match (u16::try_from(image.width()), u16::try_from(image.height())) {
+        (Ok(width), Ok(height)) => (width, height),
+        _ => {
+            return Err(CollageError::Encode(format!(
+                "{}x{} is too large for a JPEG",
+                image.width(),
+                image.height()
+            )))
+        }
+    };
+
+    let mut encoder = jpeg_encoder::Encoder::new(bytes, options.quality);
+    encoder.set_sampling_factor(match options.chroma_subsampling {
+        ChromaSubsampling::Full => SamplingFactor::R_4_4_4,
+        ChromaSubsampling::Half => SamplingFactor::R_4_2_2,
+        ChromaSubsampling::Quarter => SamplingFactor::R_4_2_0,
+    });
+    encoder
+        .encode(image.as_raw(), width, height, jpeg_encoder::ColorType::Rgba)
+        .map_err(|err| CollageError::Encode(err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode_with(format: OutputFormat) -> EncodedImage {
+        let image = RgbaImage::from_pixel(3, 2, image::Rgba([255, 0, 0, 255]));
+        let options = OutputOptions {
+            format,
+            ..OutputOptions::default()
+        };
+
+        encode(&image, &options).unwrap()
+    }
+
+    #[test]
+    fn encoded_images_decode_back_with_the_same_dimensions() {
+        use image::GenericImageView;
+
+        for format in [OutputFormat::Jpeg, OutputFormat::Png] {
+            let encoded = encode_with(format);
+            let decoded = image::load_from_memory(&encoded.bytes).unwrap();
+
+            assert_eq!((3, 2), decoded.dimensions(), "{:?}", format);
+        }
+
+        // The WebP decoder in image 0.23 doesn't support lossless images.
+        let encoded = encode_with(OutputFormat::WebP);
+        let decoder = image_webp::WebPDecoder::new(std::io::Cursor::new(encoded.bytes)).unwrap();
+
+        assert_eq!((3, 2), decoder.dimensions());
+    }
+
+    #[test]
+    fn mime_type_matches_the_format() {
+        assert_eq!("image/jpeg", encode_with(OutputFormat::Jpeg).mime_type);
+        assert_eq!("image/png", encode_with(OutputFormat::Png).mime_type);
+        assert_eq!("image/webp", encode_with(OutputFormat::WebP).mime_type);
+    }
+
+    #[test]
+    fn png_output_is_lossless() {
+        let image = RgbaImage::from_fn(4, 4, |x, y| {
+            image::Rgba([x as u8 * 60, y as u8 * 60, 7, 255])
+        });
+        let options = OutputOptions {
+            format: OutputFormat::Png,
+            ..OutputOptions::default()
+        };
+
+        let encoded = encode(&image, &options).unwrap();
+        let decoded = image::load_from_memory(&encoded.bytes).unwrap();
+
+        assert_eq!(image::ColorType::Rgb8, decoded.color());
+        assert_eq!(image, decoded.into_rgba8());
+    }
+
+    #[test]
+    fn png_output_keeps_transparency() {
+        let image = RgbaImage::from_fn(4, 4, |x, _| image::Rgba([255, 0, 0, x as u8 * 60]));
+        let options = OutputOptions {
+            format: OutputFormat::Png,
+            ..OutputOptions::default()
+        };
+
+        let encoded = encode(&image, &options).unwrap();
+
+        assert_eq!(
+            image,
+            image::load_from_memory(&encoded.bytes)
+                .unwrap()
+                .into_rgba8()
+        );
+    }
+
+    #[test]
+    fn jpeg_quality_out_of_range_is_rejected() {
+        let image = RgbaImage::new(1, 1);
+        let options = OutputOptions {
+            quality: 0,
+            ..OutputOptions::default()
+        };
+
+        assert!(matches!(
+            encode(&image, &options),
+            Err(CollageError::InvalidOptions(_))
+        ));
+    }
+}
@@ -0,0 +1,154 @@
+// A quick layout for collages with too many images for the genetic algorithm, which needs tens of
+// thousands of cost evaluations, each of them walking the whole tree.
+//
+// The images are sorted by their aspect ratio and split in half over and over again. Each half
+// gets a share of the collage proportional to the original size of its images, so that they keep
+// their relative sizes. At each split, both directions are tried and the one that brings the
+// shape of the result closer to the shape of the collage wins. Similar images stay together,
+// e.g. a row of panoramas next to a column of portraits.
+//
+// Trying both directions at every split makes it quadratic in the number of images, which is
+// still nothing compared to the genetic algorithm. The result only depends on the images and the
+// options.
+
+use crate::blueprint::LayoutBlueprint;
+use crate::cost::CostFunction;
+use crate::error::CollageError;
+use crate::exact::{self, Tree};
+use crate::layout::Layout;
+use crate::options::LayoutOptions;
+use image::RgbaImage;
+use std::rc::Rc;
+
+pub fn generate_layout<'a>(
+    images: &'a [RgbaImage],
+    options: &LayoutOptions,
+    cost_function: &dyn CostFunction,
+) -> Result<Layout<'a>, CollageError> {
+    if images.len() < 2 {
+        return Err(CollageError::TooFewImages(images.len()));
+    }
+
+    let aspect_ratios: Vec<f64> = images
+        .iter()
+        .map(|image| image.width() as f64 / image.height() as f64)
+        .collect();
+    let sizes: Vec<f64> = images
+        .iter()
+        .map(|image| image.width() as f64 * image.height() as f64)
+        .collect();
+    let mut order: Vec<usize> = (0..images.len()).collect();
+    order.sort_by(|a, b| aspect_ratios[*a].total_cmp(&aspect_ratios[*b]));
+
+    // Without a locked aspect ratio, the collage takes the shape of an average image.
+    let target = options.aspect_ratio.map_or_else(
+        || aspect_ratios.iter().sum::<f64>() / aspect_ratios.len() as f64,
+        |aspect_ratio| aspect_ratio.value(),
+    );
+    let partitioner = Partitioner {
+        aspect_ratios,
+        sizes,
+    };
+    let (tree, _) = partitioner.partition(&order, target);
+    let blueprint = LayoutBlueprint::new(1, 1, exact::to_nodes(&tree));
+    let layout = Layout::from_blueprint(&blueprint, images)?;
+
+    Ok(exact::fit_canvas(layout, options, cost_function).0)
+}
+
+struct Partitioner {
+    aspect_ratios: Vec<f64>,
+    sizes: Vec<f64>,
+}
+
+impl Partitioner {
+    // Lays out the images in an area of the given aspect ratio. Returns the tree along with its
+    // actual aspect ratio, ignoring the gutters.
+    fn partition(&self, images: &[usize], target: f64) -> (Rc<Tree>, f64) {
+        if let [image] = images {
+            return (Rc::new(Tree::Image(*image)), self.aspect_ratios[*image]);
+        }
+
+        let (left, right) = images.split_at(images.len() / 2);
+        let size = |images: &[usize]| images.iter().map(|image| self.sizes[*image]).sum::<f64>();
+        let left_share = size(left) / size(images);
+        let right_share = 1.0 - left_share;
+
+        // Side by side, the children share the height, so each gets a part of the width.
+        let (vertical_left, vertical_left_ratio) = self.partition(left, target * left_share);
+        let (vertical_right, vertical_right_ratio) = self.partition(right, target * right_share);
+        let vertical = (
+            Rc::new(Tree::Split("V", vec![vertical_left, vertical_right])),
+            vertical_left_ratio + vertical_right_ratio,
+        );
+
+        // On top of each other, the children share the width, so each gets a part of the height.
+        let (top, top_ratio) = self.partition(left, target / left_share);
+        let (bottom, bottom_ratio) = self.partition(right, target / right_share);
+        let horizontal = (
+            Rc::new(Tree::Split("H", vec![top, bottom])),
+            1.0 / (1.0 / top_ratio + 1.0 / bottom_ratio),
+        );
+
+        closest([vertical, horizontal], target)
+    }
+}
+
+// The first of the trees with the aspect ratio closest to the target. Twice as wide is as far off
+// as twice as tall.
+fn closest(candidates: impl IntoIterator<Item = (Rc<Tree>, f64)>, target: f64) -> (Rc<Tree>, f64) {
+    let deviation = |ratio: f64| (ratio / target).max(target / ratio);
+
+    candidates
+        .into_iter()
+        .reduce(|best, candidate| {
+            if deviation(candidate.1) < deviation(best.1) {
+                candidate
+            } else {
+                best
+            }
+        })
+        .expect("No trees to choose from")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cost::WeightedCost;
+
+    #[test]
+    fn square_images_form_a_grid() {
+        let images = vec![RgbaImage::new(100, 100); 4];
+        let layout =
+            generate_layout(&images, &LayoutOptions::default(), &WeightedCost::default()).unwrap();
+
+        assert_eq!("V(H(0,1),H(2,3))", layout.to_expression());
+        assert_eq!((200, 200), layout.dimensions());
+        assert_eq!(0.0, layout.cost());
+    }
+
+    #[test]
+    fn locked_aspect_ratio_shapes_the_layout() {
+        let images = vec![RgbaImage::new(100, 100); 8];
+        let options = LayoutOptions {
+            aspect_ratio: Some("2:1".parse().unwrap()),
+            ..LayoutOptions::default()
+        };
+        let layout = generate_layout(&images, &options, &WeightedCost::default()).unwrap();
+
+        assert_eq!((400, 200), layout.dimensions());
+        assert_eq!(0.0, layout.cost());
+    }
+
+    #[test]
+    fn many_images_are_laid_out_quickly() {
+        let images: Vec<RgbaImage> = (0..100)
+            .map(|i| RgbaImage::new(40 + i % 7 * 10, 40 + i % 5 * 10))
+            .collect();
+        let layout =
+            generate_layout(&images, &LayoutOptions::default(), &WeightedCost::default()).unwrap();
+
+        assert_eq!(100, layout.leaf_nodes().count());
+        assert!(layout.cost().is_finite());
+    }
+}
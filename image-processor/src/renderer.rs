@@ -1,15 +1,131 @@
-use crate::layout::{ChildSide::*, Layout, NodeLabel::*, SliceDirection::*};
-use image::{GenericImage, RgbImage};
+use crate::image_for_processing::ImageForProcessing;
+use crate::layout::{ChildSide::*, Dimensions, Layout, NodeLabel::*, SliceDirection::*};
+use crate::options::{FocalPoint, RenderOptions};
+use crate::styling;
+use image::{GenericImage, RgbaImage};
 use itertools::Itertools;
-use web_sys::console;
+use serde::Serialize;
+use std::borrow::Cow;
 
 #[derive(Debug)]
-struct Point {
-    x: u32,
-    y: u32,
+pub(crate) struct Point {
+    pub x: u32,
+    pub y: u32,
 }
 
-pub fn render_layout(layout: &Layout) -> RgbImage {
+// Where each of the images ended up in the rendered collage. Serialized to JSON as
+// `{width, height, tiles: [{image_index, x, y, width, height, scale, crop}]}`, so that the front
+// end can tell which image is under the cursor. `crop` is left out unless the image was cropped.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct LayoutMetadata {
+    pub width: u32,
+    pub height: u32,
+    pub tiles: Vec<Tile>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct Tile {
+    // Index of the image in the list of images that the layout was made from.
+    pub image_index: usize,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    // How much the image was resized to fit in the tile, 0.5 means half of the original size.
+    pub scale: f64,
+    // The part of the image shown in the tile. None if all of it is.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crop: Option<Crop>,
+}
+
+// A rectangle in pixels of the original image.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct Crop {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Crop {
+    // The biggest part of the image with the same shape as the tile, from the middle of the image.
+    pub fn centered(image: Dimensions, tile: Dimensions) -> Self {
+        Crop::around(image, tile, FocalPoint::CENTER)
+    }
+
+    // Same as `centered`, except that the part is centered on the focal point, as far as the edges
+    // of the image allow.
+    pub fn around(image: Dimensions, tile: Dimensions, focal_point: FocalPoint) -> Self {
+        let tile_aspect_ratio = tile.width as f64 / tile.height as f64;
+        let width =
+            ((image.height as f64 * tile_aspect_ratio).round() as u32).clamp(1, image.width);
+        let height =
+            ((image.width as f64 / tile_aspect_ratio).round() as u32).clamp(1, image.height);
+
+        Crop {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        }
+        .moved_to(image, focal_point)
+    }
+
+    // Keeps the size of the crop, but centers it on the focal point, as far as the edges of the
+    // image allow.
+    fn moved_to(self, image: Dimensions, focal_point: FocalPoint) -> Self {
+        let offset = |focus: f64, side: u32, part: u32| {
+            ((focus * side as f64 - part as f64 / 2.0).round().max(0.0) as u32).min(side - part)
+        };
+
+        Crop {
+            x: offset(focal_point.x, image.width, self.width),
+            y: offset(focal_point.y, image.height, self.height),
+            ..self
+        }
+    }
+}
+
+impl LayoutMetadata {
+    // Moves the crop of each tile onto the focal point of its image. `focal_points` has an entry
+    // for each image, or none at all to leave the crops centered.
+    pub fn focus(&mut self, images: &[RgbaImage], focal_points: &[FocalPoint]) {
+        if focal_points.is_empty() {
+            return;
+        }
+
+        for tile in self.tiles.iter_mut() {
+            if let Some(crop) = tile.crop {
+                tile.crop = Some(crop.moved_to(
+                    Dimensions::from_tuple(images[tile.image_index].dimensions()),
+                    focal_points[tile.image_index],
+                ));
+            }
+        }
+    }
+}
+
+impl Tile {
+    fn coords(&self) -> Point {
+        Point {
+            x: self.x,
+            y: self.y,
+        }
+    }
+
+    fn dimensions(&self) -> Dimensions {
+        Dimensions {
+            width: self.width,
+            height: self.height,
+        }
+    }
+}
+
+pub fn render_layout(layout: &Layout, options: &RenderOptions) -> RgbaImage {
+    render_tiles(layout.images(), &layout_metadata(layout), options)
+}
+
+pub fn layout_metadata(layout: &Layout) -> LayoutMetadata {
     // Canvas dimensions stored on the layout are just a side effect of how the original algorithm
     // is described in the paper. The paper assumes that the canvas size is always known upfront.
     // But in our case we want to be as big as possible without scaling the images up or down too
@@ -17,23 +133,44 @@ pub fn render_layout(layout: &Layout) -> RgbImage {
     //
     // So the canvas dimensions are more like just auxiliary values that are meant to help us get to
     // the optimal layout. It's rare that the generated dimensions are 100% equal to canvas
-    // dimensions. So instead of including bars of black pixels, the final image can just have the
+    // dimensions. So instead of including bars of background, the final image can just have the
     // actual generated dimensions.
-    let (width, height) = layout.dimensions();
-    let mut result = RgbImage::new(width, height);
-
-    console::group_1(&"Rendering layout".into());
+    //
+    // The exception is a locked aspect ratio, where the output has to have the shape of the canvas.
+    // The images are then centered on the canvas.
+    //
+    // If the layout is still too big for the size limits, everything is scaled down here, so that
+    // each image is resampled only once.
+    let layout_dimensions = Dimensions::from_tuple(layout.dimensions());
+    let canvas_dimensions = if layout.options().aspect_ratio.is_some() {
+        layout.canvas_dimensions
+    } else {
+        layout_dimensions
+    };
+    let margin = layout.options().margin;
+    let gutter = layout.options().gutter;
+    let offset = Point {
+        x: canvas_dimensions
+            .width
+            .saturating_sub(layout_dimensions.width)
+            / 2
+            + margin,
+        y: canvas_dimensions
+            .height
+            .saturating_sub(layout_dimensions.height)
+            / 2
+            + margin,
+    };
+    let scale = layout.options().scale_to_fit(canvas_dimensions).min(1.0);
+    let Dimensions { width, height } = layout.options().fit(canvas_dimensions);
 
     for internal_node in layout.internal_nodes() {
-        console::log_1(
-            &format!(
-                "{:?}, {:?}, {:?}, {}",
-                internal_node.index,
-                internal_node.node_label(),
-                internal_node.dimensions().to_tuple(),
-                internal_node.aspect_ratio()
-            )
-            .into(),
+        log::trace!(
+            "{:?}, {:?}, {:?}, {}",
+            internal_node.index,
+            internal_node.node_label(),
+            internal_node.dimensions().to_tuple(),
+            internal_node.aspect_ratio()
         );
     }
 
@@ -41,51 +178,331 @@ pub fn render_layout(layout: &Layout) -> RgbImage {
     //
     // 1. collect each parent up to the root node and save its node label and calculated size
     // 2. traverse that path from the root node, calculating the position based on the size
-    // 3. render the image on the canvas
+    let mut tiles = Vec::with_capacity(layout.leaf_nodes().count());
+
     for leaf_node in layout.leaf_nodes() {
-        let mut coords = Point { x: 0, y: 0 };
+        let mut coords = Point {
+            x: offset.x,
+            y: offset.y,
+        };
         for (parent, child) in leaf_node.lineage().iter().tuple_windows() {
             let other_child_dimensions = parent.other_child(child).unwrap().dimensions();
             let child_side = parent.child_side(child).unwrap();
 
             match (parent.node_label(), child_side) {
-                (Internal(Horizontal), Right) => coords.y += other_child_dimensions.height,
-                (Internal(Vertical), Right) => coords.x += other_child_dimensions.width,
+                (Internal(Horizontal), Right) => coords.y += other_child_dimensions.height + gutter,
+                (Internal(Vertical), Right) => coords.x += other_child_dimensions.width + gutter,
                 _ => {}
             }
         }
 
         let dimensions = leaf_node.dimensions();
         let image = leaf_node.image().unwrap();
+        let image_dimensions = Dimensions::from_tuple(image.dimensions());
+
+        // Scaling the edges rather than the sizes makes sure that adjacent images still touch
+        // after rounding.
+        let scale_edge = |edge: u32, limit: u32| ((edge as f64 * scale).round() as u32).min(limit);
+        let left = scale_edge(coords.x, width);
+        let top = scale_edge(coords.y, height);
+        let right = scale_edge(coords.x + dimensions.width, width);
+        let bottom = scale_edge(coords.y + dimensions.height, height);
 
-        console::log_1(
-            &format!(
-                "{:?}, {:?}, {:?}, {:?}, {}",
-                leaf_node.index,
-                leaf_node.node_label(),
-                dimensions.to_tuple(),
-                coords,
-                leaf_node.aspect_ratio()
-            )
-            .into(),
+        log::trace!(
+            "{:?}, {:?}, {:?}, {:?}, {}",
+            leaf_node.index,
+            leaf_node.node_label(),
+            (right - left, bottom - top),
+            (left, top),
+            leaf_node.aspect_ratio()
         );
 
-        if dimensions.to_tuple() == image.dimensions() {
-            result.copy_from(image, coords.x, coords.y).unwrap();
+        let tile = Dimensions {
+            width: right - left,
+            height: bottom - top,
+        };
+        // Without a crop tolerance, tiles only differ from the shape of their image because of
+        // rounding, which isn't worth cropping for.
+        let crop = Some(Crop::centered(image_dimensions, tile)).filter(|crop| {
+            layout.options().crop_tolerance > 0.0
+                && (crop.width, crop.height) != image_dimensions.to_tuple()
+        });
+
+        tiles.push(Tile {
+            image_index: leaf_node
+                .image_index()
+                .expect("Leaf node with an image from outside of the layout"),
+            x: left,
+            y: top,
+            width: tile.width,
+            height: tile.height,
+            scale: tile.width as f64 / crop.map_or(image.width(), |crop| crop.width) as f64,
+            crop,
+        });
+    }
+
+    tiles.sort_by_key(|tile| tile.image_index);
+
+    LayoutMetadata {
+        width,
+        height,
+        tiles,
+    }
+}
+
+// Draws the images at the positions given by the tiles, cropped if the tiles say so. `images` need
+// to be in the same order as the images that the tiles were made for.
+pub fn render_tiles(
+    images: &[RgbaImage],
+    metadata: &LayoutMetadata,
+    options: &RenderOptions,
+) -> RgbaImage {
+    let mut result = RgbaImage::from_pixel(
+        metadata.width,
+        metadata.height,
+        options.background.to_rgba(),
+    );
+    let visible_tiles = metadata
+        .tiles
+        .iter()
+        .filter(|tile| tile.dimensions().size() > 0);
+
+    // All shadows go first, so that they don't cover the neighbouring images.
+    if let Some(shadow) = &options.tile.shadow {
+        for tile in visible_tiles.clone() {
+            styling::draw_shadow(
+                &mut result,
+                &tile.coords(),
+                tile.dimensions(),
+                &options.tile,
+                shadow,
+            );
+        }
+    }
+
+    for tile in visible_tiles {
+        let image = match tile.crop {
+            Some(crop) => Cow::Owned(
+                image::imageops::crop_imm(
+                    &images[tile.image_index],
+                    crop.x,
+                    crop.y,
+                    crop.width,
+                    crop.height,
+                )
+                .to_image(),
+            ),
+            None => Cow::Borrowed(&images[tile.image_index]),
+        };
+        let image = image.as_ref();
+        let mut resized_image = if (tile.width, tile.height) == image.dimensions() {
+            Cow::Borrowed(image)
         } else {
-            let resized_image = image::imageops::resize(
+            Cow::Owned(image::imageops::resize(
                 image,
-                dimensions.width,
-                dimensions.height,
+                tile.width,
+                tile.height,
                 image::imageops::FilterType::Lanczos3,
-            );
-            result
-                .copy_from(&resized_image, coords.x, coords.y)
-                .unwrap();
+            ))
         };
-    }
 
-    console::group_end();
+        if options.tile.corner_radius > 0 || options.tile.stroke.is_some() {
+            styling::style_tile(resized_image.to_mut(), &options.tile);
+        }
+
+        paste(&mut result, &resized_image, tile.coords());
+    }
 
     result
 }
+
+// Images with transparency are blended with the background. Most images are fully opaque though,
+// and for those a plain copy is much faster than blending each pixel.
+pub(crate) fn paste(target: &mut RgbaImage, image: &RgbaImage, coords: Point) {
+    if image.is_opaque() {
+        target.copy_from(image, coords.x, coords.y).unwrap();
+    } else {
+        image::imageops::overlay(target, image, coords.x, coords.y);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::create_blueprint_from_slice;
+    use crate::options::LayoutOptions;
+    use image::Rgba;
+
+    const RED: Rgba<u8> = Rgba([255, 0, 0, 255]);
+    const BLUE: Rgba<u8> = Rgba([0, 0, 255, 255]);
+
+    fn images() -> Vec<RgbaImage> {
+        vec![
+            RgbaImage::from_pixel(100, 100, RED),
+            RgbaImage::from_pixel(400, 200, BLUE),
+        ]
+    }
+
+    #[test]
+    fn metadata_describes_the_position_of_each_image() {
+        let blueprint = create_blueprint_from_slice((300, 100), &[("V", &[])]);
+        let images = images();
+        let layout = Layout::from_blueprint(&blueprint, &images).unwrap();
+
+        let metadata = layout_metadata(&layout);
+
+        assert_eq!((300, 100), (metadata.width, metadata.height));
+        assert_eq!(
+            vec![
+                Tile {
+                    image_index: 0,
+                    x: 0,
+                    y: 0,
+                    width: 100,
+                    height: 100,
+                    scale: 1.0,
+                    crop: None,
+                },
+                Tile {
+                    image_index: 1,
+                    x: 100,
+                    y: 0,
+                    width: 200,
+                    height: 100,
+                    scale: 0.5,
+                    crop: None,
+                },
+            ],
+            metadata.tiles
+        );
+    }
+
+    #[test]
+    fn images_are_rendered_where_the_metadata_says() {
+        let blueprint = create_blueprint_from_slice((300, 100), &[("V", &[])]);
+        let images = images();
+        let layout = Layout::from_blueprint(&blueprint, &images).unwrap();
+
+        let rendered = render_layout(&layout, &RenderOptions::default());
+
+        assert_eq!((300, 100), rendered.dimensions());
+        assert_eq!(RED, *rendered.get_pixel(50, 50));
+        assert_eq!(BLUE, *rendered.get_pixel(250, 50));
+    }
+
+    #[test]
+    fn images_are_cropped_to_fill_their_tiles_with_crop_tolerance() {
+        let blueprint = create_blueprint_from_slice((300, 100), &[("V", &[])]);
+        let images = vec![RgbaImage::new(100, 100), RgbaImage::new(100, 100)];
+        let layout = Layout::from_blueprint(&blueprint, &images)
+            .unwrap()
+            .with_options(LayoutOptions {
+                crop_tolerance: 0.5,
+                ..LayoutOptions::default()
+            });
+
+        let mut metadata = layout_metadata(&layout);
+
+        assert_eq!((300, 100), (metadata.width, metadata.height));
+        assert_eq!((150, 100), metadata.tiles[0].dimensions().to_tuple());
+        assert_eq!(1.5, metadata.tiles[0].scale);
+        assert_eq!(
+            Some(Crop {
+                x: 0,
+                y: 17,
+                width: 100,
+                height: 67,
+            }),
+            metadata.tiles[0].crop
+        );
+
+        metadata.focus(
+            &images,
+            &[FocalPoint { x: 0.5, y: 0.0 }, FocalPoint { x: 0.5, y: 1.0 }],
+        );
+
+        assert_eq!(0, metadata.tiles[0].crop.unwrap().y);
+        assert_eq!(33, metadata.tiles[1].crop.unwrap().y);
+    }
+
+    #[test]
+    fn cropped_tiles_show_only_part_of_the_image() {
+        let mut image = RgbaImage::from_pixel(200, 100, RED);
+        image::imageops::replace(&mut image, &RgbaImage::from_pixel(100, 100, BLUE), 100, 0);
+        let metadata = LayoutMetadata {
+            width: 50,
+            height: 50,
+            tiles: vec![Tile {
+                image_index: 0,
+                x: 0,
+                y: 0,
+                width: 50,
+                height: 50,
+                scale: 0.5,
+                crop: Some(Crop {
+                    x: 100,
+                    y: 0,
+                    width: 100,
+                    height: 100,
+                }),
+            }],
+        };
+
+        let rendered = render_tiles(&[image], &metadata, &RenderOptions::default());
+
+        assert_eq!(BLUE, *rendered.get_pixel(0, 0));
+        assert_eq!(BLUE, *rendered.get_pixel(49, 49));
+    }
+
+    #[test]
+    fn centered_crop_has_the_shape_of_the_tile() {
+        let image = Dimensions::from_tuple((400, 200));
+
+        assert_eq!(
+            Crop {
+                x: 100,
+                y: 0,
+                width: 200,
+                height: 200,
+            },
+            Crop::centered(image, Dimensions::from_tuple((50, 50)))
+        );
+        assert_eq!(
+            Crop {
+                x: 0,
+                y: 50,
+                width: 400,
+                height: 100,
+            },
+            Crop::centered(image, Dimensions::from_tuple((80, 20)))
+        );
+    }
+
+    #[test]
+    fn metadata_serializes_to_json() {
+        let metadata = LayoutMetadata {
+            width: 10,
+            height: 20,
+            tiles: vec![Tile {
+                image_index: 3,
+                x: 1,
+                y: 2,
+                width: 3,
+                height: 4,
+                scale: 0.25,
+                crop: None,
+            }],
+        };
+
+        assert_eq!(
+            serde_json::json!({
+                "width": 10,
+                "height": 20,
+                "tiles": [
+                    {"image_index": 3, "x": 1, "y": 2, "width": 3, "height": 4, "scale": 0.25}
+                ]
+            }),
+            serde_json::to_value(&metadata).unwrap()
+        );
+    }
+}
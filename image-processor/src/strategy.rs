@@ -0,0 +1,160 @@
+// The ways of coming up with a layout. Each of them has its sweet spot:
+//
+//     exact      the best layout there is, but only for up to 7 images, see the exact module
+//     genetic    good layouts for any number of images, but slow for lots of them
+//     partition  an instant, decent layout for any number of images, see the partition module
+//
+// By default, the strategy is picked based on the number of images.
+
+use crate::algorithm::{self, SearchProgress};
+use crate::cost::CostFunction;
+use crate::error::CollageError;
+use crate::exact;
+use crate::layout::Layout;
+use crate::options::{GeneticParams, LayoutOptions};
+use crate::partition;
+use image::RgbaImage;
+use rand_core::SeedableRng;
+use rand_pcg::Pcg64;
+use serde::Deserialize;
+use std::ops::ControlFlow;
+use std::sync::Arc;
+
+// From this many images on, the genetic algorithm takes too long, especially in the browser.
+pub const PARTITION_THRESHOLD: usize = 30;
+
+pub trait LayoutStrategy<'a> {
+    // Returns the progress of the search along with the layout if the strategy reports one.
+    fn generate_layout(
+        &mut self,
+        images: &'a [RgbaImage],
+        options: &LayoutOptions,
+        cost_function: Arc<dyn CostFunction>,
+    ) -> Result<(Layout<'a>, Option<SearchProgress>), CollageError>;
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Strategy {
+    // Exact up to `GeneticParams::exact_search_limit` images, partition from PARTITION_THRESHOLD
+    // images on and genetic in between.
+    #[default]
+    Auto,
+    Exact,
+    Genetic,
+    Partition,
+}
+
+impl Strategy {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "auto" => Some(Strategy::Auto),
+            "exact" => Some(Strategy::Exact),
+            "genetic" => Some(Strategy::Genetic),
+            "partition" => Some(Strategy::Partition),
+            _ => None,
+        }
+    }
+
+    // Picks a specific strategy in place of Auto.
+    pub fn resolve(self, image_count: usize, params: &GeneticParams) -> Self {
+        match self {
+            Strategy::Auto if image_count <= params.exact_search_limit => Strategy::Exact,
+            Strategy::Auto if image_count >= PARTITION_THRESHOLD => Strategy::Partition,
+            Strategy::Auto => Strategy::Genetic,
+            strategy => strategy,
+        }
+    }
+}
+
+pub struct Exact;
+
+impl<'a> LayoutStrategy<'a> for Exact {
+    fn generate_layout(
+        &mut self,
+        images: &'a [RgbaImage],
+        options: &LayoutOptions,
+        cost_function: Arc<dyn CostFunction>,
+    ) -> Result<(Layout<'a>, Option<SearchProgress>), CollageError> {
+        let layout = exact::generate_layout(images, options, cost_function.as_ref())?;
+
+        Ok((layout, None))
+    }
+}
+
+// See LayoutSearch for `on_progress`. Without a seed, the search is seeded randomly.
+pub struct Genetic<F> {
+    pub params: GeneticParams,
+    pub seed: Option<u64>,
+    pub on_progress: F,
+}
+
+impl<'a, F> LayoutStrategy<'a> for Genetic<F>
+where
+    F: FnMut(&SearchProgress, &Layout<'a>) -> ControlFlow<()>,
+{
+    fn generate_layout(
+        &mut self,
+        images: &'a [RgbaImage],
+        options: &LayoutOptions,
+        cost_function: Arc<dyn CostFunction>,
+    ) -> Result<(Layout<'a>, Option<SearchProgress>), CollageError> {
+        let (layout, search) = match self.seed {
+            Some(seed) => {
+                let mut rng = Pcg64::seed_from_u64(seed);
+                algorithm::generate_layout(
+                    images,
+                    options,
+                    &self.params,
+                    cost_function,
+                    &mut rng,
+                    Some(seed),
+                    &mut self.on_progress,
+                )?
+            }
+            None => algorithm::generate_layout(
+                images,
+                options,
+                &self.params,
+                cost_function,
+                &mut rand::thread_rng(),
+                None,
+                &mut self.on_progress,
+            )?,
+        };
+
+        Ok((layout, Some(search)))
+    }
+}
+
+pub struct Partition;
+
+impl<'a> LayoutStrategy<'a> for Partition {
+    fn generate_layout(
+        &mut self,
+        images: &'a [RgbaImage],
+        options: &LayoutOptions,
+        cost_function: Arc<dyn CostFunction>,
+    ) -> Result<(Layout<'a>, Option<SearchProgress>), CollageError> {
+        let layout = partition::generate_layout(images, options, cost_function.as_ref())?;
+
+        Ok((layout, None))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auto_strategy_depends_on_the_number_of_images() {
+        let params = GeneticParams::default();
+        let resolve = |image_count| Strategy::Auto.resolve(image_count, &params);
+
+        assert_eq!(Strategy::Exact, resolve(params.exact_search_limit));
+        assert_eq!(Strategy::Genetic, resolve(params.exact_search_limit + 1));
+        assert_eq!(Strategy::Genetic, resolve(PARTITION_THRESHOLD - 1));
+        assert_eq!(Strategy::Partition, resolve(PARTITION_THRESHOLD));
+        assert_eq!(Strategy::Genetic, Strategy::Genetic.resolve(3, &params));
+    }
+}
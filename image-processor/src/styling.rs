@@ -0,0 +1,271 @@
+// Per-tile decorations: rounded corners, strokes and drop shadows.
+//
+// Edges are anti-aliased by computing the signed distance from each pixel center to the edge of
+// a rounded rectangle. A pixel which the edge goes right through ends up half covered, and so on.
+
+use crate::layout::Dimensions;
+use crate::options::{Color, Shadow, TileStyle};
+use crate::renderer::Point;
+use image::{Rgba, RgbaImage};
+
+// Rounds the corners of an already resized tile and draws the stroke on top of it.
+pub fn style_tile(image: &mut RgbaImage, style: &TileStyle) {
+    if style.corner_radius == 0 && style.stroke.is_none() {
+        return;
+    }
+
+    let shape = RoundedRect::new(
+        Dimensions::from_tuple(image.dimensions()),
+        style.corner_radius,
+    );
+
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        let distance = shape.distance(x as f32 + 0.5, y as f32 + 0.5);
+
+        if let Some(stroke) = style.stroke {
+            let stroke_coverage = coverage(distance) - coverage(distance + stroke.width as f32);
+
+            blend(pixel, stroke.color, stroke_coverage);
+        }
+
+        pixel[3] = (pixel[3] as f32 * coverage(distance)).round() as u8;
+    }
+}
+
+// Draws the shadow of a tile with the given position and size. Shadows of all tiles need to be
+// drawn before any of the tiles, otherwise they'd cover the neighbouring tiles.
+pub fn draw_shadow(
+    target: &mut RgbaImage,
+    coords: &Point,
+    dimensions: Dimensions,
+    style: &TileStyle,
+    shadow: &Shadow,
+) {
+    // The mask needs some room around the tile for the blur to fade out.
+    let padding = shadow.blur;
+    let mask_width = (dimensions.width + padding * 2) as usize;
+    let mask_height = (dimensions.height + padding * 2) as usize;
+    let shape = RoundedRect::new(dimensions, style.corner_radius);
+
+    let mut mask: Vec<f32> = (0..mask_width * mask_height)
+        .map(|i| {
+            let x = (i % mask_width) as f32 - padding as f32;
+            let y = (i / mask_width) as f32 - padding as f32;
+
+            coverage(shape.distance(x + 0.5, y + 0.5))
+        })
+        .collect();
+
+    // Three passes of a box blur are a good enough approximation of a gaussian blur and, unlike
+    // the gaussian blur from the image crate, they take the same time regardless of the radius.
+    let radius = (shadow.blur / 3) as usize;
+    if radius > 0 {
+        for _ in 0..3 {
+            mask = box_blur(&mask, mask_width, mask_height, radius);
+        }
+    }
+
+    let left = coords.x as i64 + shadow.offset_x as i64 - padding as i64;
+    let top = coords.y as i64 + shadow.offset_y as i64 - padding as i64;
+
+    for (i, shadow_coverage) in mask.into_iter().enumerate() {
+        let x = left + (i % mask_width) as i64;
+        let y = top + (i / mask_width) as i64;
+
+        if x < 0 || y < 0 || x >= target.width() as i64 || y >= target.height() as i64 {
+            continue;
+        }
+
+        blend(
+            target.get_pixel_mut(x as u32, y as u32),
+            shadow.color,
+            shadow_coverage,
+        );
+    }
+}
+
+struct RoundedRect {
+    half_width: f32,
+    half_height: f32,
+    radius: f32,
+}
+
+impl RoundedRect {
+    fn new(dimensions: Dimensions, radius: u32) -> Self {
+        let half_width = dimensions.width as f32 / 2.0;
+        let half_height = dimensions.height as f32 / 2.0;
+
+        RoundedRect {
+            half_width,
+            half_height,
+            radius: (radius as f32).min(half_width).min(half_height),
+        }
+    }
+
+    // Negative inside of the rectangle, positive outside. The rectangle starts at (0, 0).
+    fn distance(&self, x: f32, y: f32) -> f32 {
+        let qx = (x - self.half_width).abs() - (self.half_width - self.radius);
+        let qy = (y - self.half_height).abs() - (self.half_height - self.radius);
+        let outside = qx.max(0.0).hypot(qy.max(0.0));
+        let inside = qx.max(qy).min(0.0);
+
+        outside + inside - self.radius
+    }
+}
+
+// How much of a pixel is covered by a shape, given the distance of the pixel center to its edge.
+fn coverage(distance: f32) -> f32 {
+    (0.5 - distance).clamp(0.0, 1.0)
+}
+
+// Paints the color over the pixel, with `amount` being an additional opacity between 0 and 1.
+fn blend(pixel: &mut Rgba<u8>, color: Color, amount: f32) {
+    let source_alpha = color.0[3] as f32 / 255.0 * amount;
+
+    if source_alpha <= 0.0 {
+        return;
+    }
+
+    let target_alpha = pixel[3] as f32 / 255.0;
+    let alpha = source_alpha + target_alpha * (1.0 - source_alpha);
+
+    for channel in 0..3 {
+        let value = (color.0[channel] as f32 * source_alpha
+            + pixel[channel] as f32 * target_alpha * (1.0 - source_alpha))
+            / alpha;
+
+        pixel[channel] = value.round() as u8;
+    }
+
+    pixel[3] = (alpha * 255.0).round() as u8;
+}
+
+fn box_blur(values: &[f32], width: usize, height: usize, radius: usize) -> Vec<f32> {
+    let horizontal = blur_lines(values, width, height, radius, Orientation::Rows);
+
+    blur_lines(&horizontal, width, height, radius, Orientation::Columns)
+}
+
+enum Orientation {
+    Rows,
+    Columns,
+}
+
+// Averages each value with `radius` values on both sides, using prefix sums so that the cost
+// doesn't depend on the radius. Values past the edges count as zero.
+fn blur_lines(
+    values: &[f32],
+    width: usize,
+    height: usize,
+    radius: usize,
+    orientation: Orientation,
+) -> Vec<f32> {
+    let (line_count, line_length) = match orientation {
+        Orientation::Rows => (height, width),
+        Orientation::Columns => (width, height),
+    };
+    let index = |line: usize, position: usize| match orientation {
+        Orientation::Rows => line * width + position,
+        Orientation::Columns => position * width + line,
+    };
+    let window = (radius * 2 + 1) as f32;
+    let mut result = vec![0.0; values.len()];
+    let mut prefix_sums = vec![0.0; line_length + 1];
+
+    for line in 0..line_count {
+        for position in 0..line_length {
+            prefix_sums[position + 1] = prefix_sums[position] + values[index(line, position)];
+        }
+
+        for position in 0..line_length {
+            let start = position.saturating_sub(radius);
+            let end = (position + radius + 1).min(line_length);
+
+            result[index(line, position)] = (prefix_sums[end] - prefix_sums[start]) / window;
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::Stroke;
+
+    const WHITE: Rgba<u8> = Rgba([255, 255, 255, 255]);
+
+    #[test]
+    fn rounding_corners_makes_them_transparent() {
+        let mut image = RgbaImage::from_pixel(20, 20, WHITE);
+        let style = TileStyle {
+            corner_radius: 8,
+            ..TileStyle::default()
+        };
+
+        style_tile(&mut image, &style);
+
+        assert_eq!(0, image.get_pixel(0, 0)[3]);
+        assert_eq!(0, image.get_pixel(19, 19)[3]);
+        assert_eq!(WHITE, *image.get_pixel(10, 0));
+        assert_eq!(WHITE, *image.get_pixel(10, 10));
+    }
+
+    #[test]
+    fn rounded_corners_are_anti_aliased() {
+        let mut image = RgbaImage::from_pixel(20, 20, WHITE);
+        let style = TileStyle {
+            corner_radius: 8,
+            ..TileStyle::default()
+        };
+
+        style_tile(&mut image, &style);
+
+        // Somewhere along the diagonal the edge of the corner crosses the pixel.
+        assert!((0..8).any(|i| (1..255).contains(&image.get_pixel(i, i)[3])));
+    }
+
+    #[test]
+    fn stroke_is_drawn_inside_of_the_tile() {
+        let mut image = RgbaImage::from_pixel(20, 20, WHITE);
+        let style = TileStyle {
+            stroke: Some(Stroke {
+                width: 2,
+                color: Color::BLACK,
+            }),
+            ..TileStyle::default()
+        };
+
+        style_tile(&mut image, &style);
+
+        assert_eq!(Rgba([0, 0, 0, 255]), *image.get_pixel(0, 10));
+        assert_eq!(Rgba([0, 0, 0, 255]), *image.get_pixel(1, 10));
+        assert_eq!(WHITE, *image.get_pixel(2, 10));
+    }
+
+    #[test]
+    fn shadow_fades_out_around_the_tile() {
+        let mut target = RgbaImage::from_pixel(60, 60, WHITE);
+        let shadow = Shadow {
+            offset_x: 0,
+            offset_y: 0,
+            blur: 9,
+            color: Color::BLACK,
+        };
+
+        draw_shadow(
+            &mut target,
+            &Point { x: 20, y: 20 },
+            Dimensions::from_tuple((20, 20)),
+            &TileStyle::default(),
+            &shadow,
+        );
+
+        let brightness = |x: u32| target.get_pixel(x, 30)[0];
+
+        assert_eq!(0, brightness(30));
+        assert!(brightness(20) < brightness(17));
+        assert!(brightness(17) < brightness(14));
+        assert_eq!(255, brightness(5));
+    }
+}
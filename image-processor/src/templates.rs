@@ -0,0 +1,224 @@
+// Classic hand-designed layouts, for when a predictable grid is preferred over whatever the
+// genetic algorithm comes up with. Each template is defined for 2 to 12 images, e.g. for four
+// images:
+//
+//     grid         H(V(0,1),V(2,3))     ┌──┬──┐
+//                                       ├──┼──┤
+//                                       └──┴──┘
+//
+//     hero-strip   H(0,V(1,V(2,3)))     ┌─────┐
+//                                       ├─┬─┬─┤
+//                                       └─┴─┴─┘
+//
+//     hero-column  V(0,H(1,H(2,3)))     ┌───┬─┐
+//                                       │   ├─┤
+//                                       │   ├─┤
+//                                       └───┴─┘
+//
+//     mosaic       V(H(0,1),H(2,3))     the images are split in half, alternating between
+//                                       vertical and horizontal splits
+//
+// The first image is the one that gets featured in hero templates. The templates are also used to
+// seed the initial population of the genetic algorithm.
+
+use crate::blueprint::LayoutBlueprint;
+use crate::error::CollageError;
+use crate::layout::{Dimensions, Layout};
+use image::RgbaImage;
+use serde::Deserialize;
+
+pub const MIN_IMAGES: usize = 2;
+pub const MAX_IMAGES: usize = 12;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Template {
+    // Rows of roughly the same number of images, e.g. 2x2 or 3x3.
+    Grid,
+    // The first image on top of a single row with the rest of them.
+    HeroStrip,
+    // The first image next to a single column with the rest of them.
+    HeroColumn,
+    Mosaic,
+}
+
+impl Template {
+    pub const ALL: [Template; 4] = [
+        Template::Grid,
+        Template::HeroStrip,
+        Template::HeroColumn,
+        Template::Mosaic,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Template::ALL
+            .iter()
+            .copied()
+            .find(|template| template.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Template::Grid => "grid",
+            Template::HeroStrip => "hero-strip",
+            Template::HeroColumn => "hero-column",
+            Template::Mosaic => "mosaic",
+        }
+    }
+
+    // The slicing tree of the template, see the expression module for the syntax.
+    pub fn expression(&self, image_count: usize) -> Option<String> {
+        if !(MIN_IMAGES..=MAX_IMAGES).contains(&image_count) {
+            return None;
+        }
+
+        let images: Vec<String> = (0..image_count).map(|image| image.to_string()).collect();
+
+        Some(match self {
+            Template::Grid => {
+                let column_count = (image_count as f64).sqrt().ceil() as usize;
+                let row_count = image_count.div_ceil(column_count);
+                let mut rows = vec![];
+                let mut start = 0;
+
+                // Spread the images evenly, so that the last row isn't left with a single one.
+                for row in 0..row_count {
+                    let length =
+                        image_count / row_count + usize::from(row < image_count % row_count);
+
+                    rows.push(join("V", &images[start..start + length]));
+                    start += length;
+                }
+
+                join("H", &rows)
+            }
+            Template::HeroStrip => format!("H({},{})", images[0], join("V", &images[1..])),
+            Template::HeroColumn => format!("V({},{})", images[0], join("H", &images[1..])),
+            Template::Mosaic => mosaic(&images, "V"),
+        })
+    }
+
+    pub fn blueprint(&self, images: &[RgbaImage]) -> Result<LayoutBlueprint, CollageError> {
+        Ok(self.layout(images)?.to_blueprint())
+    }
+
+    // The canvas gets the shape of the template and roughly the same area as all of the images
+    // combined, so that they're shown close to their original size.
+    pub fn layout<'a>(&self, images: &'a [RgbaImage]) -> Result<Layout<'a>, CollageError> {
+        if images.len() < MIN_IMAGES {
+            return Err(CollageError::TooFewImages(images.len()));
+        }
+
+        let expression = self.expression(images.len()).ok_or_else(|| {
+            CollageError::InvalidOptions(format!(
+                "The {} template supports at most {} images, received {}",
+                self.name(),
+                MAX_IMAGES,
+                images.len()
+            ))
+        })?;
+        let blueprint = LayoutBlueprint::from_expression(&expression, 1, 1)?;
+        let mut layout = Layout::from_blueprint(&blueprint, images)?;
+
+        let area: f64 = images
+            .iter()
+            .map(|image| image.width() as f64 * image.height() as f64)
+            .sum();
+        let aspect_ratio = layout.aspect_ratio();
+        let width = (area * aspect_ratio).sqrt();
+
+        layout.canvas_dimensions = Dimensions {
+            width: (width.round() as u32).max(1),
+            height: ((width / aspect_ratio).round() as u32).max(1),
+        };
+
+        Ok(layout)
+    }
+}
+
+// Combines the parts into a balanced tree of splits in the given direction.
+fn join(split: &str, parts: &[String]) -> String {
+    if parts.len() == 1 {
+        return parts[0].clone();
+    }
+
+    let (left, right) = parts.split_at(parts.len() / 2);
+
+    format!("{}({},{})", split, join(split, left), join(split, right))
+}
+
+fn mosaic(parts: &[String], split: &str) -> String {
+    if parts.len() == 1 {
+        return parts[0].clone();
+    }
+
+    let (left, right) = parts.split_at(parts.len() / 2);
+    let other_split = if split == "V" { "H" } else { "V" };
+
+    format!(
+        "{}({},{})",
+        split,
+        mosaic(left, other_split),
+        mosaic(right, other_split)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn templates_for_four_images() {
+        let expression = |template: Template| template.expression(4).unwrap();
+
+        assert_eq!("H(V(0,1),V(2,3))", expression(Template::Grid));
+        assert_eq!("H(0,V(1,V(2,3)))", expression(Template::HeroStrip));
+        assert_eq!("V(0,H(1,H(2,3)))", expression(Template::HeroColumn));
+        assert_eq!("V(H(0,1),H(2,3))", expression(Template::Mosaic));
+    }
+
+    #[test]
+    fn grid_rows_differ_by_at_most_one_image() {
+        assert_eq!(
+            "H(V(0,V(1,2)),H(V(3,4),V(5,6)))",
+            Template::Grid.expression(7).unwrap()
+        );
+    }
+
+    #[test]
+    fn every_template_is_a_valid_blueprint_for_every_supported_count() {
+        for template in Template::ALL {
+            for image_count in MIN_IMAGES..=MAX_IMAGES {
+                let images = vec![RgbaImage::new(4, 3); image_count];
+                let blueprint = template.blueprint(&images).unwrap();
+
+                assert_eq!(
+                    Ok(()),
+                    blueprint.validate(image_count),
+                    "{:?} with {} images",
+                    template,
+                    image_count
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn unsupported_counts_are_rejected() {
+        let images = vec![RgbaImage::new(1, 1); MAX_IMAGES + 1];
+
+        assert_eq!(None, Template::Grid.expression(1));
+        assert!(matches!(
+            Template::Grid.layout(&images),
+            Err(CollageError::InvalidOptions(_))
+        ));
+    }
+
+    #[test]
+    fn canvas_fits_the_images_at_their_original_size() {
+        let images = vec![RgbaImage::new(10, 10); 4];
+        let layout = Template::Grid.layout(&images).unwrap();
+
+        assert_eq!((20, 20), layout.dimensions());
+    }
+}
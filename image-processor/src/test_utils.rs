@@ -0,0 +1,19 @@
+// Fixtures shared by the tests, next to `layout::create_blueprint_from_slice`. Only depends on the
+// image crate, so that the integration tests can include it as well.
+
+use image::{Rgba, RgbaImage};
+
+// A bit of everything: landscape, portrait, square and both kinds of panoramas.
+pub(crate) const FIVE_IMAGES: [(u32, u32); 5] = [(40, 30), (30, 40), (50, 50), (60, 20), (20, 60)];
+
+// Images of the given sizes, each one a different colour so that no two of them have the same
+// hash.
+pub(crate) fn images(sizes: &[(u32, u32)]) -> Vec<RgbaImage> {
+    sizes
+        .iter()
+        .enumerate()
+        .map(|(i, &(width, height))| {
+            RgbaImage::from_pixel(width, height, Rgba([i as u8, 0, 0, 255]))
+        })
+        .collect()
+}
@@ -8,3 +8,32 @@ pub fn set_panic_hook() {
     #[cfg(feature = "console_error_panic_hook")]
     console_error_panic_hook::set_once();
 }
+
+pub fn set_logger() {
+    // When the `console` feature is enabled, everything logged through the `log` crate ends up in
+    // the developer console.
+    #[cfg(all(feature = "console", target_arch = "wasm32"))]
+    crate::console::init(log::LevelFilter::Debug);
+}
+
+// Measures how long a step takes and logs it once stopped. chrono is used instead of
+// std::time::Instant, as the latter panics in the browser.
+pub struct Timer {
+    label: String,
+    start: chrono::DateTime<chrono::Utc>,
+}
+
+impl Timer {
+    pub fn start(label: impl Into<String>) -> Self {
+        Timer {
+            label: label.into(),
+            start: chrono::Utc::now(),
+        }
+    }
+
+    pub fn stop(self) {
+        let elapsed = chrono::Utc::now() - self.start;
+
+        log::debug!("{}: {}ms", self.label, elapsed.num_milliseconds());
+    }
+}
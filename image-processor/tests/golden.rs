@@ -0,0 +1,115 @@
+//! The same seed, images and options have to give the same layout on every target, debug and
+//! release builds included, as seeds from bug reports are used to reproduce layouts exactly.
+//!
+//! If a change to the layout algorithm is intentional, update the expected blueprints below.
+//!
+//! `cargo test` covers native builds. For WebAssembly, run the same tests in Node with
+//! `yarn test-wasm` from the root of the repo, which needs wasm-pack. That hasn't been verified yet,
+//! so far these tests have only been run natively.
+
+use image::RgbaImage;
+use image_processor::options::GeneticPreset;
+use image_processor::{CollageOptions, GeneticParams, LayoutBlueprint};
+
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen_test::wasm_bindgen_test as test;
+
+#[path = "../src/test_utils.rs"]
+mod test_utils;
+
+use test_utils::{images, FIVE_IMAGES};
+
+fn blueprint(images: Vec<RgbaImage>, seed: u64, options: &CollageOptions) -> (String, u32, u32) {
+    let blueprint: LayoutBlueprint = image_processor::generate_collage(images, Some(seed), options)
+        .unwrap()
+        .blueprint
+        .unwrap();
+
+    (
+        blueprint.to_expression().unwrap(),
+        blueprint.width,
+        blueprint.height,
+    )
+}
+
+// Five images would go through the exact search otherwise.
+fn genetic_only(params: GeneticParams) -> GeneticParams {
+    GeneticParams {
+        exact_search_limit: 0,
+        ..params
+    }
+}
+
+#[test]
+fn fast_preset() {
+    let options = CollageOptions {
+        genetic: genetic_only(GeneticParams::preset(GeneticPreset::Fast)),
+        ..CollageOptions::default()
+    };
+
+    assert_eq!(
+        ("H(V(0,1),H(V(2,4),3))".to_string(), 68, 107),
+        blueprint(images(&FIVE_IMAGES), 1338, &options)
+    );
+}
+
+// Big enough for genevo to breed the offspring in parallel on native targets.
+#[test]
+fn large_population() {
+    let options = CollageOptions {
+        genetic: genetic_only(GeneticParams {
+            population_size: 80,
+            generation_limit: 60,
+            ..GeneticParams::default()
+        }),
+        ..CollageOptions::default()
+    };
+
+    assert_eq!(
+        ("V(H(2,3),V(H(1,0),4))".to_string(), 104, 67),
+        blueprint(images(&FIVE_IMAGES), 42, &options)
+    );
+}
+
+// The seed makes no difference here.
+#[test]
+fn exact_search() {
+    let options = CollageOptions::default();
+
+    assert_eq!(
+        ("H(V(H(0,1),4),H(2,3))".to_string(), 51, 130),
+        blueprint(images(&FIVE_IMAGES), 1, &options)
+    );
+    assert_eq!(
+        blueprint(images(&FIVE_IMAGES), 1, &options),
+        blueprint(images(&FIVE_IMAGES), 2, &options)
+    );
+}
+
+#[test]
+fn locked_aspect_ratio_with_gutters() {
+    let mut options = CollageOptions {
+        genetic: GeneticParams::preset(GeneticPreset::Fast),
+        ..CollageOptions::default()
+    };
+    options.layout.aspect_ratio = Some("16:9".parse().unwrap());
+    options.layout.gutter = 8;
+
+    assert_eq!(
+        ("H(V(V(2,6),4),H(V(V(5,1),7),V(0,3)))".to_string(), 378, 213),
+        blueprint(
+            images(&[
+                (64, 48),
+                (48, 64),
+                (100, 100),
+                (120, 40),
+                (30, 90),
+                (80, 60),
+                (60, 80),
+                (50, 70)
+            ]),
+            7,
+            &options
+        )
+    );
+}
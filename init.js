@@ -8,7 +8,10 @@ const app = Elm.Main.init({
 })
 
 app.ports.sendImagesToJs.subscribe((files) => {
-  generateCollage(files).catch(console.error)
+  generateCollage(files).catch((error) => {
+    console.error(error)
+    app.ports.imageProcessorError.send(error.message)
+  })
 })
 
 const benchmarkSeed = 1338;
@@ -31,7 +34,7 @@ worker.onmessage = (event) => {
   }
 }
 
-const generateCollage = async (files, seed) => {
+const generateCollage = async (files, seed, options) => {
   URL.revokeObjectURL(resultImg.src);
   resultImg.src = "";
 
@@ -40,11 +43,11 @@ const generateCollage = async (files, seed) => {
   )
 
   console.time('generate_layout');
-  const resultArray = await generate_layout(imageArrays, seed);
+  const result = await generate_layout(imageArrays, seed, options);
   console.timeEnd('generate_layout');
 
   resultImg.src = URL.createObjectURL(
-    new Blob([resultArray.buffer], {type: 'image/jpg'})
+    new Blob([result.bytes.buffer], {type: result.mime_type})
   );
 
   app.ports.imageProcessorStatus.send("done");
@@ -52,17 +55,54 @@ const generateCollage = async (files, seed) => {
 
 // Wrapping the message passing in a promise.
 // The worker code is simple enough that we can let ourselves do that.
-const generate_layout = (imageArrays, seed) => new Promise((resolve, reject) => {
-  worker.onmessage = (event) => { resolve(event.data) }
+const settle = (resolve, reject, onProgress) => (event) => {
+  const [status, payload] = event.data;
+
+  if (status == 'progress') {
+    // {generation, generation_limit, best_cost, improved, elapsed_ms, stop_reason}, plus the
+    // blueprint of the best layout when it changes.
+    if (onProgress) {
+      onProgress(payload)
+    }
+  } else if (status == 'ok') {
+    resolve(payload)
+  } else {
+    reject(new Error(payload))
+  }
+}
+
+// The worker is busy until the search is done, so cancelling goes through memory shared with it
+// instead of a message. Shared memory needs a cross-origin isolated page, elsewhere the time limit
+// from the genetic options is the only way to stop a search early.
+const cancelFlag = self.crossOriginIsolated ? new Int32Array(new SharedArrayBuffer(4)) : null;
+
+// Makes the running generate_layout resolve with the best layout found so far, with the
+// stop_reason of the search set to 'cancelled'. Returns false if cancelling isn't supported.
+const cancelLayoutGeneration = () => {
+  if (!cancelFlag) {
+    return false
+  }
+
+  Atomics.store(cancelFlag, 0, 1)
+  return true
+}
+
+// `options` is optional, e.g. {output: {format: 'png'}, genetic: 'fast'}.
+const generate_layout = (imageArrays, seed, options, onProgress) => new Promise((resolve, reject) => {
+  if (cancelFlag) {
+    Atomics.store(cancelFlag, 0, 0)
+  }
+
+  worker.onmessage = settle(resolve, reject, onProgress)
   worker.postMessage(
-    ['generate_layout', imageArrays, seed],
+    ['generate_layout', imageArrays, seed, options, cancelFlag],
     imageArrays.map((imageArray) => imageArray.buffer)
   )
 })
-const render_specific_layout = (layoutBlueprint, imageArrays) => new Promise((resolve, reject) => {
-  worker.onmessage = (event) => { resolve(event.data) }
+const render_specific_layout = (layoutBlueprint, imageArrays, options) => new Promise((resolve, reject) => {
+  worker.onmessage = settle(resolve, reject)
   worker.postMessage(
-    ['render_specific_layout', layoutBlueprint, imageArrays],
+    ['render_specific_layout', layoutBlueprint, imageArrays, options],
     imageArrays.map((imageArray) => imageArray.buffer)
   )
 })
@@ -83,7 +123,7 @@ const orientationTest = async () => {
       URL.revokeObjectURL(this.src);
     }
     img.src = URL.createObjectURL(
-      new Blob([result.buffer], {type: 'image/jpg'})
+      new Blob([result.bytes.buffer], {type: result.mime_type})
     );
 
     document.body.appendChild(img);
@@ -143,10 +183,10 @@ const blueprintLayoutTest = async () => {
     '175.jpg', '170.jpg', '220.jpg', '192.jpg', '200.jpg', '140.jpg', '302.jpg'
   ].map((name) => loadFile(name)))
 
-  const resultArray = await render_specific_layout(layoutBlueprint, images);
+  const result = await render_specific_layout(layoutBlueprint, images);
 
   resultImg.src = URL.createObjectURL(
-    new Blob([resultArray.buffer], {type: 'image/jpg'})
+    new Blob([result.bytes.buffer], {type: result.mime_type})
   );
 }
 
@@ -14,6 +14,9 @@ port sendImagesToJs : List D.Value -> Cmd msg
 port imageProcessorStatus : (String -> msg) -> Sub msg
 
 
+port imageProcessorError : (String -> msg) -> Sub msg
+
+
 main =
     Browser.element
         { init = init
@@ -40,6 +43,7 @@ type Status
 
 type ProcessingError
     = LessThanTwoImages
+    | ImageProcessorFailed String
 
 
 init : () -> ( Model, Cmd a )
@@ -54,6 +58,7 @@ init _ =
 type Msg
     = GotFiles (List D.Value)
     | ImageProcessorStatusUpdated
+    | ImageProcessorErrored String
 
 
 update : Msg -> Model -> ( Model, Cmd Msg )
@@ -69,10 +74,16 @@ update msg model =
         ImageProcessorStatusUpdated ->
             ( Done, Cmd.none )
 
+        ImageProcessorErrored message ->
+            ( Error (ImageProcessorFailed message), Cmd.none )
+
 
 subscriptions _ =
-    -- For now we only really handle the happy path, hence `always`.
-    imageProcessorStatus (always ImageProcessorStatusUpdated)
+    Sub.batch
+        [ -- The status port only ever reports success, hence `always`.
+          imageProcessorStatus (always ImageProcessorStatusUpdated)
+        , imageProcessorError ImageProcessorErrored
+        ]
 
 
 
@@ -89,6 +100,9 @@ view model =
                 Error LessThanTwoImages ->
                     p [] [ text "Can't make a collage with just one image" ]
 
+                Error (ImageProcessorFailed message) ->
+                    p [] [ text ("Couldn't make a collage: " ++ message) ]
+
                 _ ->
                     text ""
     in
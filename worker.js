@@ -15,19 +15,36 @@ onmessage = function(event) {
 
   switch (action) {
     case 'generate_layout': {
-      const imageArrays = payload[0];
-      const seed = payload[1];
-      const result = generate_layout(imageArrays, seed);
-      postMessage(result, [result.buffer]);
+      const [imageArrays, seed, options, cancelFlag] = payload;
+      // The search runs synchronously, so the worker can't receive any messages until it's done.
+      // Instead, the page can stop it by setting the shared cancel flag, which is checked after
+      // each generation. Returning false makes the search stop with the best layout so far.
+      const onProgress = (progress) => {
+        postMessage(['progress', progress]);
+        return !(cancelFlag && Atomics.load(cancelFlag, 0) == 1);
+      };
+      respond(() => generate_layout(imageArrays, seed, options, onProgress));
       break;
     }
     case 'render_specific_layout': {
-      const [layoutBlueprint, imageArrays] = payload;
-      const result = render_specific_layout(layoutBlueprint, imageArrays);
-      postMessage(result, [result.buffer]);
+      const [layoutBlueprint, imageArrays, options] = payload;
+      respond(() => render_specific_layout(layoutBlueprint, imageArrays, options));
       break;
     }
     default:
       throw new Error(`Unknown action: ${action}`)
   }
 }
+
+// The image processor throws on invalid input, so the error is passed back to the main thread
+// which can then reject the promise.
+const respond = (fn) => {
+  try {
+    // The result looks like {bytes: Uint8Array, mime_type: string, layout: object,
+    // blueprint: object or null, search: object or null}.
+    const result = fn();
+    postMessage(['ok', result], [result.bytes.buffer]);
+  } catch (error) {
+    postMessage(['error', error.message]);
+  }
+}